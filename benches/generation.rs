@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wfc_rust::wfc::{
+  tile_map::{PropagationOrder, TileMap},
+  tile_type::TileType,
+};
+
+const SEED: u64 = 42;
+const SIZES: [i32; 3] = [32, 64, 128];
+
+/**
+ * Generates square maps with the default rules from a fixed seed.
+ * Throughput is in collapses, so the report shows collapses per second.
+ * Each size also prints how many cells were updated while propagating and how many updates were
+ * skipped because the cell was already waiting, so changes to propagation can be compared by count too.
+ * Running with `--features linear-entropy-scan` times the scan over every cell that the entropy queue
+ * replaced, which picks the same cells, so the two runs can be compared size by size.
+ */
+fn generation(c: &mut Criterion) {
+  let mut group = c.benchmark_group("generate");
+  group.sample_size(10);
+  group.measurement_time(Duration::from_secs(10));
+
+  for size in SIZES {
+    let mut map = TileMap::new(size, size, TileType::default_rules());
+    map
+      .generate_with_seed(SEED)
+      .expect("the benchmark seed should generate");
+    let stats = map.stats();
+    println!(
+      "generate/{}: {} updates, {} skipped as already waiting",
+      size, stats.propagations, stats.skipped_updates
+    );
+    group.throughput(Throughput::Elements(stats.collapses as u64));
+
+    group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+      b.iter(|| map.generate_with_seed(SEED).unwrap());
+    });
+  }
+
+  group.finish();
+}
+
+/**
+ * Generates the same 64 by 64 map with each propagation order.
+ */
+fn propagation_order(c: &mut Criterion) {
+  let mut group = c.benchmark_group("propagation_order");
+  group.sample_size(10);
+  group.measurement_time(Duration::from_secs(10));
+
+  for order in [PropagationOrder::BreadthFirst, PropagationOrder::DepthFirst] {
+    let mut map = TileMap::new(64, 64, TileType::default_rules());
+    map.propagation_order = order;
+    map
+      .generate_with_seed(SEED)
+      .expect("the benchmark seed should generate");
+    group.throughput(Throughput::Elements(map.stats().collapses as u64));
+
+    group.bench_with_input(
+      BenchmarkId::new(format!("{:?}", order), 64),
+      &order,
+      |b, _| {
+        b.iter(|| map.generate_with_seed(SEED).unwrap());
+      },
+    );
+  }
+
+  group.finish();
+}
+
+criterion_group!(benches, generation, propagation_order);
+criterion_main!(benches);
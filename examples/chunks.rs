@@ -0,0 +1,51 @@
+use wfc_rust::wfc::{
+  tile_map::{Direction, TileMap},
+  tile_type::TileType,
+};
+
+const CHUNK_SIZE: i32 = 16;
+const CHUNKS: i32 = 4;
+const ATTEMPTS: u64 = 10;
+
+/**
+ * Generates a row of chunks that line up at their seams and prints them side by side.
+ * Pass a world seed as the first argument to generate the same chunks again.
+ */
+fn main() {
+  let seed = match std::env::args().nth(1) {
+    Some(seed) => seed.parse().expect("the seed should be a number"),
+    None => rand::random(),
+  };
+
+  let mut chunk = TileMap::new(CHUNK_SIZE, CHUNK_SIZE, TileType::default_rules());
+  let mut chunks = Vec::new();
+
+  for chunk_x in 0..CHUNKS {
+    if chunk_x > 0 {
+      chunk = chunk.generate_adjacent(&Direction::East);
+    }
+
+    let chunk_seed = TileMap::chunk_seed(seed, chunk_x, 0);
+    // Retry with derived seeds so the row stays the same for the same world seed.
+    let generated = (0..ATTEMPTS).any(|attempt| {
+      chunk
+        .generate_with_seed(chunk_seed.wrapping_add(attempt))
+        .is_ok()
+    });
+
+    if !generated {
+      eprintln!("Chunk {} of seed {} could not be generated", chunk_x, seed);
+      std::process::exit(1);
+    }
+
+    chunks.push(chunk.to_ascii(&TileType::legend()));
+  }
+
+  println!("seed {}", seed);
+
+  let rows: Vec<Vec<&str>> = chunks.iter().map(|chunk| chunk.lines().collect()).collect();
+  for y in 0..CHUNK_SIZE as usize {
+    let row: Vec<&str> = rows.iter().map(|lines| lines[y]).collect();
+    println!("{}", row.join(" "));
+  }
+}
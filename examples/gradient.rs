@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+
+use wfc_rust::wfc::{
+  tile_map::{Position, TileMap},
+  tile_type::TileType,
+};
+
+const MAP_WIDTH: i32 = 40;
+const MAP_HEIGHT: i32 = 20;
+
+/**
+ * Water gets more likely towards the south edge and stone towards the north edge.
+ */
+fn biomes(position: &Position) -> HashMap<TileType, f32> {
+  let south = position.y as f32 / (MAP_HEIGHT - 1) as f32;
+
+  let mut modifiers = HashMap::new();
+  modifiers.insert(TileType::Water, 0.2 + south * 3.0);
+  modifiers.insert(TileType::Stone, 0.1 + (1.0 - south) * 2.0);
+  modifiers
+}
+
+/**
+ * Generates a map with a weight gradient and prints it as text, the south edge at the bottom.
+ * Pass a seed as the first argument to generate the same map again.
+ */
+fn main() {
+  let seed = match std::env::args().nth(1) {
+    Some(seed) => seed.parse().expect("the seed should be a number"),
+    None => rand::random(),
+  };
+
+  let mut map = TileMap::new(MAP_WIDTH, MAP_HEIGHT, TileType::default_rules());
+  map.weight_modifier = Some(biomes);
+
+  if let Err(error) = map.generate_with_seed(seed) {
+    eprintln!("Generating with seed {} failed: {}", seed, error);
+    std::process::exit(1);
+  }
+
+  println!("seed {}", seed);
+  print!("{}", map.to_ascii(&TileType::legend()));
+}
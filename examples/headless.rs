@@ -0,0 +1,32 @@
+use wfc_rust::wfc::{tile_map::TileMap, tile_type::TileType};
+
+const MAP_WIDTH: i32 = 40;
+const MAP_HEIGHT: i32 = 20;
+
+/**
+ * Generates a map without a window and prints it as text.
+ * Pass a seed as the first argument to generate the same map again.
+ */
+fn main() {
+  let seed = match std::env::args().nth(1) {
+    Some(seed) => seed.parse().expect("the seed should be a number"),
+    None => rand::random(),
+  };
+
+  let rules = TileType::default_rules();
+  if let Err(errors) = rules.validate() {
+    for error in errors {
+      eprintln!("{}", error);
+    }
+    std::process::exit(1);
+  }
+
+  let mut map = TileMap::new(MAP_WIDTH, MAP_HEIGHT, rules);
+  if let Err(error) = map.generate_with_seed(seed) {
+    eprintln!("Generating with seed {} failed: {}", seed, error);
+    std::process::exit(1);
+  }
+
+  println!("seed {}", seed);
+  print!("{}", map.to_ascii(&TileType::legend()));
+}
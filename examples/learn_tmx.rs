@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use wfc_rust::wfc::{learn::learn_rules_from_grid, tile_map::TileMap, tile_type::TileType, tiled};
+
+const MAP_WIDTH: i32 = 40;
+const MAP_HEIGHT: i32 = 20;
+const ATTEMPTS: usize = 10;
+
+/**
+ * Learns rules from a map made in Tiled and prints a map generated from them.
+ * Pass the path of a `.tmx` file as the first argument, its `ground` layer is used.
+ */
+fn main() {
+  let path = std::env::args()
+    .nth(1)
+    .unwrap_or_else(|| "assets/maps/island.tmx".to_string());
+  let tmx = std::fs::read_to_string(&path).expect("the map should be readable");
+
+  let grid = tiled::layer_from_tmx(&tmx, "ground")
+    .and_then(|gids| tiled::grid_from_gids(&gids, &lookup()))
+    .unwrap_or_else(|error| {
+      eprintln!("Can't read {}: {}", path, error);
+      std::process::exit(1);
+    });
+
+  let mut map = TileMap::new(MAP_WIDTH, MAP_HEIGHT, learn_rules_from_grid(&grid));
+  if let Err(error) = map.generate_or_retry(ATTEMPTS) {
+    eprintln!("Generating from {} failed: {}", path, error);
+    std::process::exit(1);
+  }
+
+  println!("seed {}", map.seed());
+  print!("{}", map.to_ascii(&TileType::legend()));
+}
+
+/**
+ * The tile types of the tiles in the example map's tileset, by their id in the map.
+ */
+fn lookup() -> HashMap<u32, TileType> {
+  HashMap::from([
+    (1, TileType::Grass),
+    (2, TileType::Water),
+    (3, TileType::Sand),
+    (4, TileType::Trees),
+    (5, TileType::Stone),
+  ])
+}
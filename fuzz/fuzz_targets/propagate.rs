@@ -0,0 +1,108 @@
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use wfc_rust::wfc::{
+  tile_map::{Position, TileMap},
+  tile_set::TileSet,
+  tile_type::{AdjacencyRule, TileRules, TileType, DEFAULT_TILESET_COLUMNS, DEFAULT_TILE_SIZE},
+};
+
+/**
+ * Reads the next byte of the input, running out of input reads zeroes.
+ */
+fn next(bytes: &mut impl Iterator<Item = u8>) -> u8 {
+  bytes.next().unwrap_or(0)
+}
+
+/**
+ * A set of types picked by the low bits of a byte.
+ */
+fn tile_set(bits: u8) -> TileSet {
+  TileType::all_types()
+    .into_iter()
+    .enumerate()
+    .filter(|(index, _)| bits & (1 << index) != 0)
+    .map(|(_, tile_type)| tile_type)
+    .collect()
+}
+
+/**
+ * Rules with arbitrary adjacency sets and weights, the other rules are left empty.
+ */
+fn arbitrary_rules(bytes: &mut impl Iterator<Item = u8>) -> TileRules {
+  let mut adjacency = HashMap::new();
+  let mut weights = HashMap::new();
+
+  for tile_type in TileType::all_types() {
+    let rule = AdjacencyRule::new(
+      tile_set(next(bytes)),
+      tile_set(next(bytes)),
+      tile_set(next(bytes)),
+      tile_set(next(bytes)),
+    );
+    adjacency.insert(tile_type.clone(), rule);
+    weights.insert(tile_type, (next(bytes) % 4) as f32);
+  }
+
+  TileRules {
+    adjacency,
+    weights,
+    cleanup: Vec::new(),
+    tags: HashMap::new(),
+    tag_adjacency: Vec::new(),
+    merged: HashMap::new(),
+    texture_rules: Vec::new(),
+    forbidden_corners: Vec::new(),
+    directional_weights: HashMap::new(),
+    metadata: HashMap::new(),
+    tile_width: DEFAULT_TILE_SIZE,
+    tile_height: DEFAULT_TILE_SIZE,
+    tileset_columns: DEFAULT_TILESET_COLUMNS,
+  }
+}
+
+fuzz_target!(|data: &[u8]| {
+  let mut bytes = data.iter().copied();
+  let width = 1 + (next(&mut bytes) % 8) as i32;
+  let height = 1 + (next(&mut bytes) % 8) as i32;
+  let wrap = next(&mut bytes) % 2 == 1;
+
+  let mut map = TileMap::new(width, height, arbitrary_rules(&mut bytes));
+  map.wrap = wrap;
+  map.reseed(0);
+
+  let types = TileType::all_types();
+  while bytes.len() >= 3 {
+    let position = Position {
+      x: (next(&mut bytes) as i32) % width,
+      y: (next(&mut bytes) as i32) % height,
+      z: 0,
+    };
+    let tile_type = types[next(&mut bytes) as usize % types.len()].clone();
+
+    // A failed collapse leaves the map as it was, so either way the map has to be consistent.
+    let result = map.collapse_at(position.clone(), tile_type.clone());
+    if let Err(error) = map.check_invariants() {
+      panic!(
+        "collapsing {:?} to {:?} returned {:?} but left {:?}",
+        position, tile_type, result, error
+      );
+    }
+  }
+
+  // Updating a cell by hand either narrows it or reports the contradiction without touching it.
+  for y in 0..height {
+    for x in 0..width {
+      let position = Position { x, y, z: 0 };
+      let result = map.update_cell(position.clone());
+      if let Err(error) = map.check_invariants() {
+        panic!(
+          "updating {:?} returned {:?} but left {:?}",
+          position, result, error
+        );
+      }
+    }
+  }
+});
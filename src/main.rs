@@ -1,21 +1,53 @@
-mod wfc;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  time::Duration,
+};
 
 use bevy::{
+  asset::{AssetLoader, AssetServerSettings, LoadContext, LoadedAsset},
   diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
   prelude::*,
+  reflect::TypeUuid,
   render::camera::ScalingMode,
-  window::PresentMode,
+  tasks::{AsyncComputeTaskPool, Task},
+  utils::BoxedFuture,
+};
+use futures_lite::future;
+use wfc_rust::wfc::{
+  cell::Cell,
+  rules_file::resolve_includes,
+  tile_map::{MapStatus, Position, TileMap, TileMapSnapshot},
+  tile_set::TileSet,
+  tile_type::{TileRules, TileType, DEFAULT_TILESET_COLUMNS, DEFAULT_TILE_SIZE},
 };
-use wfc::tile_map::TileMap;
 
 #[derive(Component)]
-struct Person;
+struct Tile;
+
+// Put on the tiles drawn for `WfcLayers`, they're redrawn apart from the terrain's.
+#[derive(Component)]
+struct LayerTile;
+
+// Put on every drawn tile with the type's tags and metadata from the rules,
+// so game systems can ask what a tile is without going by its texture.
+#[derive(Component, Clone, Debug)]
+pub struct WfcTile {
+  pub tile_type: TileType,
+  pub tags: HashSet<String>,
+  pub metadata: HashMap<String, String>,
+}
 
 #[derive(Component)]
-struct Name(String);
+struct EntropyOverlay;
 
 #[derive(Component)]
-struct Tile;
+struct ContradictionMarker;
+
+#[derive(Component)]
+struct GridLine;
+
+#[derive(Component)]
+struct InspectLabel;
 
 pub struct HelloPlugin;
 
@@ -23,13 +55,179 @@ struct RegenKey {
   pressed: bool,
 }
 
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum AppState {
+  Generating,
+  Finished,
+}
+
+// The amount of collapses done each frame while the map is generating.
+struct StepsPerFrame(usize);
+
+// Whether the map keeps stepping each frame, only used when generating in the foreground.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum WfcControl {
+  Running,
+  Paused,
+  // Runs a single collapse, then pauses again.
+  Step,
+}
+
+// Tints the cells in superposition by their entropy, toggled with E.
+struct ShowEntropy(bool);
+
+// The cells that ran out of types when generation last failed, cleared once a map is generated.
+struct Contradictions(Vec<Position>);
+
+// Draws lines along the cell edges, toggled with G.
+struct ShowGrid(bool);
+
+// Shows the state of the cell under the cursor next to it and logs it whenever it changes, toggled with I.
+struct InspectCell(bool);
+
+// The size of the generated map, read when the map is built.
+pub struct WfcConfig {
+  pub width: u32,
+  pub height: u32,
+  // The size tiles are drawn at, tiles don't have to be square.
+  // Both this and `tileset_columns` are taken from the rules file when it's loaded.
+  pub tile_size: Vec2,
+  // How many tiles wide the tileset image is, for a game drawing the tiles from one sheet.
+  pub tileset_columns: u32,
+  // Generates the whole map on a background task instead of a few steps each frame.
+  pub background: bool,
+  // The z the tiles are drawn at, the debug overlays go just above it.
+  // Lets the map sit between the layers of a game that draws its own sprites.
+  pub layer: f32,
+}
+
+impl Default for WfcConfig {
+  fn default() -> Self {
+    WfcConfig {
+      width: 50,
+      height: 50,
+      tile_size: Vec2::splat(DEFAULT_TILE_SIZE),
+      tileset_columns: DEFAULT_TILESET_COLUMNS,
+      background: false,
+      layer: 0.0,
+    }
+  }
+}
+
+// A map drawn over the terrain, generated again each time the terrain is generated.
+// Every cell is limited to the types `fits` gives for the type of the cell under it,
+// in the terrain for the first layer and in the layer before it for the others.
+pub struct WfcLayer {
+  pub map: TileMap,
+  // The z the layer is drawn at, like `WfcConfig::layer`.
+  pub layer: f32,
+  pub fits: fn(&TileType) -> TileSet,
+  // The type that stands for nothing on this layer, it isn't drawn so the layer below shows through.
+  pub empty: Option<TileType>,
+}
+
+// The layers stacked on the terrain, from the bottom up.
+pub struct WfcLayers(pub Vec<WfcLayer>);
+
+// The map being generated in the background, swapped into the `TileMap` resource once it's done.
+struct GenerationTask(Option<Task<TileMap>>);
+
+// Snapshots taken before every regeneration and painted tile, the newest at the back.
+struct UndoStack(VecDeque<TileMapSnapshot>);
+
+impl UndoStack {
+  fn push(&mut self, snapshot: TileMapSnapshot) {
+    if self.0.len() == MAX_UNDO {
+      self.0.pop_front();
+    }
+    self.0.push_back(snapshot);
+  }
+}
+
+// The type placed when clicking a tile, picked with the number keys.
+struct PaintTile(TileType);
+
+// Tile rules loaded from a `.rules` file, saved the way `TileRules::to_json` writes them.
+// The file may have `//` comments and an `include` list of other rule files it builds on,
+// edits to an included file show up the next time the file including it is saved.
+#[derive(TypeUuid)]
+#[uuid = "4b6f0c2e-8d1a-4f3b-9e57-2c60a1d9b7f4"]
+struct RulesAsset(TileRules);
+
+#[derive(Default)]
+struct RulesLoader;
+
+impl AssetLoader for RulesLoader {
+  fn load<'a>(
+    &'a self,
+    bytes: &'a [u8],
+    load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+    Box::pin(async move {
+      let json = resolve_includes(load_context.path(), bytes, |include| {
+        future::block_on(load_context.read_asset_bytes(include))
+      })?;
+      let rules = TileRules::from_json(&json)?;
+      load_context.set_default_asset(LoadedAsset::new(RulesAsset(rules)));
+      Ok(())
+    })
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &["rules"]
+  }
+}
+
+// Kept for as long as the app runs, so the rules asset stays loaded and edits to the file keep coming in.
+struct RulesHandle(Handle<RulesAsset>);
+
+// Sent every frame the map is generating, so a loading bar can follow along.
+pub struct WfcProgress {
+  pub collapsed: usize,
+  pub total: usize,
+}
+
+// Sent once the map is fully generated.
+pub struct WfcFinished {
+  pub seed: u64,
+}
+
 pub const CLEAR: Color = Color::rgb(0.1, 0.1, 0.1);
 
+// Drawn over the cells that are still in superposition while generation is paused.
+const UNDECIDED: Color = Color::rgb(0.4, 0.3, 0.5);
+
+// The ends of the entropy overlay's colour ramp.
+const LOW_ENTROPY: Color = Color::rgba(1.0, 0.1, 0.1, 0.6);
+const HIGH_ENTROPY: Color = Color::rgba(0.1, 0.2, 1.0, 0.6);
+
+const CONTRADICTION: Color = Color::rgb(1.0, 0.0, 0.0);
+
+const GRID: Color = Color::rgba(1.0, 1.0, 1.0, 0.5);
+const GRID_LINE_WIDTH: f32 = 1.0;
+
+// How far above `WfcConfig::layer` the decoration layer and each overlay are drawn.
+const DECORATION_LAYER: f32 = 0.5;
+const ENTROPY_LAYER: f32 = 1.0;
+const CONTRADICTION_LAYER: f32 = 2.0;
+const GRID_LAYER: f32 = 3.0;
+const INSPECT_LAYER: f32 = 4.0;
+
+const INSPECT_FONT_SIZE: f32 = 16.0;
+const INSPECT_TEXT: Color = Color::WHITE;
+
 const ASPECT_RATIO: f32 = 16.0 / 9.0;
 const RESOLUTION: f32 = 900.0;
 
-const MAP_HEIGHT: f32 = 50.0;
-const MAP_WIDTH: f32 = 50.0;
+const STEPS_PER_FRAME: usize = 5;
+
+const MAX_UNDO: usize = 20;
+
+// How long the background task spends on a seed before trying another one.
+const BACKGROUND_BUDGET: Duration = Duration::from_secs(10);
+
+const RULES_PATH: &str = "rules/default.rules";
+const FONT_PATH: &str = "fonts/FiraMono-Medium.ttf";
 
 fn main() {
   App::new()
@@ -40,20 +238,59 @@ fn main() {
       // present_mode: PresentMode::Fifo,
       ..Default::default()
     })
+    .insert_resource(AssetServerSettings {
+      watch_for_changes: true,
+      ..Default::default()
+    })
+    .insert_resource(WfcConfig::default())
     .insert_resource(RegenKey { pressed: false })
+    .insert_resource(StepsPerFrame(STEPS_PER_FRAME))
+    .insert_resource(WfcControl::Running)
+    .insert_resource(ShowEntropy(false))
+    .insert_resource(Contradictions(Vec::new()))
+    .insert_resource(ShowGrid(false))
+    .insert_resource(InspectCell(false))
+    .insert_resource(PaintTile(TileType::Grass))
+    .insert_resource(GenerationTask(None))
+    .insert_resource(UndoStack(VecDeque::new()))
+    .add_state(AppState::Generating)
+    .add_event::<WfcProgress>()
+    .add_event::<WfcFinished>()
     .add_plugins(DefaultPlugins)
+    .add_asset::<RulesAsset>()
+    .init_asset_loader::<RulesLoader>()
     .add_plugin(LogDiagnosticsPlugin::default())
-    .add_plugin(FrameTimeDiagnosticsPlugin::default())
-    .insert_resource(TileMap::new(
-      MAP_WIDTH.floor() as i32,
-      MAP_HEIGHT.floor() as i32,
-      wfc::tile_type::TileType::default_rules(),
-    ))
+    .add_plugin(FrameTimeDiagnosticsPlugin)
     .add_startup_system(spawn_camera)
     .add_startup_system(build_map)
+    .add_startup_system(load_rules)
+    .add_startup_system(spawn_inspect_label)
+    .add_system_set(
+      SystemSet::on_update(AppState::Generating)
+        .with_system(step_map)
+        .with_system(generate_in_background),
+    )
+    .add_system(log_stats)
     .add_system(draw_map)
+    .add_system(generate_layers)
+    .add_system(draw_layers)
     .add_system(rebuild_map)
+    .add_system(reload_rules)
     .add_system(move_camera)
+    .add_system(fit_camera)
+    .add_system(pick_paint_tile)
+    .add_system(paint_tile)
+    .add_system(undo)
+    .add_system(toggle_lock)
+    .add_system(control_generation)
+    .add_system(finish_map)
+    .add_system(toggle_entropy)
+    .add_system(draw_entropy)
+    .add_system(draw_contradictions)
+    .add_system(toggle_grid)
+    .add_system(draw_grid)
+    .add_system(toggle_inspect)
+    .add_system(inspect_cell)
     .run();
 }
 
@@ -90,21 +327,616 @@ fn move_camera(
   }
 }
 
-fn build_map(mut map: ResMut<TileMap>) {
-  map.generate();
+/**
+ * Frames the whole map, centered, when the app starts, when a map is generated and when F is pressed.
+ */
+fn fit_camera(
+  mut camera_q: Query<(&mut Transform, &mut OrthographicProjection), With<Camera>>,
+  mut finished: EventReader<WfcFinished>,
+  mut fitted: Local<bool>,
+  config: Res<WfcConfig>,
+  keys: Res<Input<KeyCode>>,
+) {
+  let generated = finished.iter().count() > 0;
+  if *fitted && !generated && !keys.just_pressed(KeyCode::F) {
+    return;
+  }
+
+  let (mut transform, mut projection) = match camera_q.get_single_mut() {
+    Ok(camera) => camera,
+    Err(_) => return,
+  };
+  *fitted = true;
+
+  let map_size = Vec2::new(config.width as f32, config.height as f32) * config.tile_size;
+  // Keep the aspect ratio of the window, the shorter side gets some empty space around the map.
+  let frame_width = map_size.x.max(map_size.y * ASPECT_RATIO);
+  let frame_size = Vec2::new(frame_width, frame_width / ASPECT_RATIO);
+  let corner = (map_size - frame_size) / 2.0;
+
+  projection.left = corner.x;
+  projection.right = corner.x + frame_size.x;
+  projection.bottom = corner.y;
+  projection.top = corner.y + frame_size.y;
+  transform.translation.x = 0.0;
+  transform.translation.y = 0.0;
+}
+
+fn pick_paint_tile(mut paint: ResMut<PaintTile>, keys: Res<Input<KeyCode>>) {
+  let number_keys = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+  ];
+
+  for (tile_type, key) in TileType::all_types().into_iter().zip(number_keys) {
+    if keys.just_pressed(key) {
+      info!("Painting {:?}", tile_type);
+      paint.0 = tile_type;
+    }
+  }
+}
+
+/**
+ * The cell under the cursor, if the cursor is in the window.
+ */
+fn cursor_position(
+  windows: &Windows,
+  (transform, projection): (&Transform, &OrthographicProjection),
+  tile_size: Vec2,
+) -> Option<Position> {
+  let window = windows.get_primary()?;
+  let cursor = window.cursor_position()?;
+
+  // Map the cursor from window pixels onto the projection, then into the world.
+  let window_size = Vec2::new(window.width(), window.height());
+  let projection_size = Vec2::new(
+    projection.right - projection.left,
+    projection.top - projection.bottom,
+  );
+  let local =
+    cursor / window_size * projection_size + Vec2::new(projection.left, projection.bottom);
+  let world = transform.mul_vec3(local.extend(0.0));
+
+  Some(Position {
+    x: (world.x / tile_size.x).floor() as i32,
+    y: (world.y / tile_size.y).floor() as i32,
+    z: 0,
+  })
+}
+
+fn paint_tile(
+  mut map: ResMut<TileMap>,
+  paint: Res<PaintTile>,
+  mut undo_stack: ResMut<UndoStack>,
+  config: Res<WfcConfig>,
+  buttons: Res<Input<MouseButton>>,
+  windows: Res<Windows>,
+  camera_q: Query<(&Transform, &OrthographicProjection), With<Camera>>,
+) {
+  if !buttons.just_pressed(MouseButton::Left) {
+    return;
+  }
+
+  let position = match cursor_position(&windows, camera_q.single(), config.tile_size) {
+    Some(position) => position,
+    None => return,
+  };
+
+  if !matches!(map.get(&position), Some(Cell::Superposition(_))) {
+    return;
+  }
+
+  undo_stack.push(map.snapshot());
+  if let Err(error) = map.collapse_at(position, paint.0.clone()) {
+    undo_stack.0.pop_back();
+    warn!("Can't place {:?} there: {}", paint.0, error);
+  }
+}
+
+fn toggle_lock(
+  mut map: ResMut<TileMap>,
+  config: Res<WfcConfig>,
+  keys: Res<Input<KeyCode>>,
+  windows: Res<Windows>,
+  camera_q: Query<(&Transform, &OrthographicProjection), With<Camera>>,
+) {
+  if !keys.just_pressed(KeyCode::L) {
+    return;
+  }
+
+  let position = match cursor_position(&windows, camera_q.single(), config.tile_size) {
+    Some(position) => position,
+    None => return,
+  };
+
+  if map.is_locked(&position) {
+    info!("Unlocked ({}, {})", position.x, position.y);
+    map.unlock(&position);
+  } else if let Some(Cell::Collapsed(_)) = map.get(&position) {
+    info!("Locked ({}, {})", position.x, position.y);
+    map.lock(position);
+  }
+}
+
+fn control_generation(mut control: ResMut<WfcControl>, keys: Res<Input<KeyCode>>) {
+  if keys.just_pressed(KeyCode::Space) {
+    *control = match *control {
+      WfcControl::Running => WfcControl::Paused,
+      WfcControl::Paused | WfcControl::Step => WfcControl::Running,
+    };
+    info!("Generation {:?}", *control);
+  }
+
+  if keys.just_pressed(KeyCode::Period) {
+    *control = WfcControl::Step;
+  }
+}
+
+/**
+ * Collapses the rest of the map right away when Enter is pressed, keeping what's been painted.
+ */
+fn finish_map(
+  mut map: ResMut<TileMap>,
+  mut task: ResMut<GenerationTask>,
+  mut state: ResMut<State<AppState>>,
+  mut finished: EventWriter<WfcFinished>,
+  keys: Res<Input<KeyCode>>,
+) {
+  if !keys.just_pressed(KeyCode::Return) || *state.current() != AppState::Generating {
+    return;
+  }
+
+  task.0 = None;
+  match map.finish() {
+    Ok(()) => {
+      info!("Finished map with seed {}", map.seed());
+      finished.send(WfcFinished { seed: map.seed() });
+      state.set(AppState::Finished).unwrap();
+    }
+    Err(error) => warn!("Can't finish the map: {}", error),
+  }
+}
+
+fn toggle_grid(mut show: ResMut<ShowGrid>, keys: Res<Input<KeyCode>>) {
+  if keys.just_pressed(KeyCode::G) {
+    show.0 = !show.0;
+  }
+}
+
+fn toggle_inspect(mut inspect: ResMut<InspectCell>, keys: Res<Input<KeyCode>>) {
+  if keys.just_pressed(KeyCode::I) {
+    inspect.0 = !inspect.0;
+  }
+}
+
+// The label moves while the camera is read, so it has to be kept apart from the camera's transform.
+type InspectLabelQuery<'w, 's> = Query<
+  'w,
+  's,
+  (
+    &'static mut Text,
+    &'static mut Transform,
+    &'static mut Visibility,
+  ),
+  (With<InspectLabel>, Without<Camera>),
+>;
+
+fn spawn_inspect_label(mut commands: Commands, asset_server: Res<AssetServer>) {
+  let style = TextStyle {
+    font: asset_server.load(FONT_PATH),
+    font_size: INSPECT_FONT_SIZE,
+    color: INSPECT_TEXT,
+  };
+  let alignment = TextAlignment {
+    vertical: VerticalAlign::Bottom,
+    horizontal: HorizontalAlign::Left,
+  };
+
+  commands
+    .spawn_bundle(Text2dBundle {
+      text: Text::with_section("", style, alignment),
+      visibility: Visibility { is_visible: false },
+      ..default()
+    })
+    .insert(InspectLabel);
+}
+
+/**
+ * Shows the cell under the cursor at its top right corner, its type and texture once it's collapsed
+ * or the types it has left and its entropy. The label is hidden while the cursor is off the map.
+ * Also logs the cell when that changes, so a cell that gets stuck can be followed while the map is stepped.
+ */
+fn inspect_cell(
+  map: Res<TileMap>,
+  inspect: Res<InspectCell>,
+  config: Res<WfcConfig>,
+  windows: Res<Windows>,
+  camera_q: Query<(&Transform, &OrthographicProjection), With<Camera>>,
+  mut label_q: InspectLabelQuery,
+  mut last: Local<Option<String>>,
+) {
+  let (mut text, mut transform, mut visibility) = label_q.single_mut();
+  if !inspect.0 {
+    visibility.is_visible = false;
+    *last = None;
+    return;
+  }
+
+  let position = cursor_position(&windows, camera_q.single(), config.tile_size);
+  let description = position.as_ref().and_then(|position| {
+    let state = match map.get(position)? {
+      Cell::Collapsed(tile_type) => match map.texture_at(position) {
+        Some((texture, 0)) => format!("{:?}, drawn with {}", tile_type, texture),
+        Some((texture, turns)) => format!(
+          "{:?}, drawn with {} turned {} quarter turns clockwise",
+          tile_type, texture, turns
+        ),
+        None => format!("{:?}, without a texture", tile_type),
+      },
+      Cell::Superposition(types) => format!(
+        "one of {:?} with an entropy of {:.3}",
+        Vec::<TileType>::from(*types),
+        map.entropy_at(position).unwrap_or(0.0)
+      ),
+    };
+    let locked = if map.is_locked(position) {
+      " (locked)"
+    } else {
+      ""
+    };
+
+    Some(format!(
+      "({}, {}) is {}{}",
+      position.x, position.y, state, locked
+    ))
+  });
+
+  visibility.is_visible = description.is_some();
+  if description == *last {
+    return;
+  }
+
+  if let (Some(description), Some(position)) = (&description, &position) {
+    info!("{}", description);
+    text.sections[0].value = description.clone();
+    transform.translation = Vec3::new(
+      (position.x + 1) as f32 * config.tile_size.x,
+      (position.y + 1) as f32 * config.tile_size.y,
+      config.layer + INSPECT_LAYER,
+    );
+  }
+  *last = description;
+}
+
+fn toggle_entropy(mut show: ResMut<ShowEntropy>, keys: Res<Input<KeyCode>>) {
+  if keys.just_pressed(KeyCode::E) {
+    show.0 = !show.0;
+  }
+}
+
+fn undo(
+  mut map: ResMut<TileMap>,
+  mut undo_stack: ResMut<UndoStack>,
+  mut task: ResMut<GenerationTask>,
+  mut state: ResMut<State<AppState>>,
+  keys: Res<Input<KeyCode>>,
+) {
+  let ctrl = keys.pressed(KeyCode::LControl) || keys.pressed(KeyCode::RControl);
+  if !ctrl || !keys.just_pressed(KeyCode::Z) {
+    return;
+  }
+
+  let snapshot = match undo_stack.0.pop_back() {
+    Some(snapshot) => snapshot,
+    None => return,
+  };
+
+  task.0 = None;
+  if let Err(error) = map.restore(snapshot) {
+    warn!("Can't undo: {}", error);
+  }
+
+  let next_state = if map.is_fully_collapsed() {
+    AppState::Finished
+  } else {
+    AppState::Generating
+  };
+  if *state.current() != next_state {
+    state.set(next_state).unwrap();
+  }
+}
+
+fn start_generation(map: &mut TileMap) {
+  map.reseed(rand::random());
+  info!("Generating map with seed {}", map.seed());
+}
+
+fn retry_generation(map: &mut TileMap) {
+  map.retry_with_seed(rand::random());
+  info!("Retrying with seed {}", map.seed());
+}
+
+fn build_map(mut commands: Commands, config: Res<WfcConfig>) {
+  let mut map = match TileMap::try_new(
+    config.width as i32,
+    config.height as i32,
+    TileType::default_rules(),
+  ) {
+    Ok(map) => map,
+    Err(error) => panic!("Can't build the map: {}", error),
+  };
+
+  commands.insert_resource(WfcLayers(vec![WfcLayer {
+    map: TileMap::new(map.width, map.height, decoration_rules()),
+    layer: config.layer + DECORATION_LAYER,
+    fits: decoration_fits,
+    empty: Some(TileType::Grass),
+  }]));
+
+  start_generation(&mut map);
+  commands.insert_resource(map);
+}
+
+/**
+ * Trees and rocks scattered over the grass, Grass is the open ground between them.
+ */
+fn decoration_rules() -> TileRules {
+  let mut rules = TileType::default_rules();
+  rules.cleanup.clear();
+  rules.weights.insert(TileType::Grass, 12.0);
+  rules.weights.insert(TileType::Trees, 2.0);
+  rules.weights.insert(TileType::Stone, 0.5);
+  rules.weights.insert(TileType::Water, 0.0);
+  rules.weights.insert(TileType::Sand, 0.0);
+  rules
+}
+
+fn decoration_fits(terrain: &TileType) -> TileSet {
+  match terrain {
+    TileType::Grass => TileSet::from_iter([TileType::Grass, TileType::Trees, TileType::Stone]),
+    _ => TileSet::from_iter([TileType::Grass]),
+  }
+}
+
+/**
+ * Generates the layers once the terrain is done, each from a seed taken from the terrain's,
+ * so they stay the same for as long as the terrain does. While the terrain is generating they're cleared.
+ */
+fn generate_layers(terrain: Res<TileMap>, mut layers: ResMut<WfcLayers>) {
+  if !terrain.is_changed() {
+    return;
+  }
+
+  if !terrain.is_fully_collapsed() {
+    if layers.0.iter().any(|layer| layer.map.collapsed_count() > 0) {
+      for layer in layers.0.iter_mut() {
+        layer.map.clear();
+      }
+    }
+    return;
+  }
+
+  let layers = &mut layers.0;
+  for index in 0..layers.len() {
+    let (lower, upper) = layers.split_at_mut(index);
+    let below = lower.last().map_or(&*terrain, |layer| &layer.map);
+    let layer = &mut upper[0];
+
+    if !below.is_fully_collapsed() {
+      layer.map.clear();
+      continue;
+    }
+
+    layer.map.clear_constraints();
+    for (position, tile_type) in below.collapsed_tiles() {
+      layer
+        .map
+        .set_allowed(position.clone(), (layer.fits)(tile_type));
+    }
+
+    let seed = terrain.seed().wrapping_add(index as u64 + 1);
+    if let Err(error) = layer.map.generate_with_seed(seed) {
+      warn!(
+        "Generating layer {} with seed {} failed: {}",
+        index, seed, error
+      );
+      layer.map.clear();
+    }
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn step_map(
+  mut map: ResMut<TileMap>,
+  config: Res<WfcConfig>,
+  steps: Res<StepsPerFrame>,
+  mut control: ResMut<WfcControl>,
+  mut contradictions: ResMut<Contradictions>,
+  mut state: ResMut<State<AppState>>,
+  mut progress: EventWriter<WfcProgress>,
+  mut finished: EventWriter<WfcFinished>,
+) {
+  if config.background {
+    return;
+  }
+
+  let steps = match *control {
+    WfcControl::Running => steps.0,
+    WfcControl::Paused => return,
+    WfcControl::Step => {
+      *control = WfcControl::Paused;
+      1
+    }
+  };
+
+  for _ in 0..steps {
+    match map.step() {
+      MapStatus::Generating => continue,
+      MapStatus::Finished => {
+        info!("Generated map with seed {}", map.seed());
+        finished.send(WfcFinished { seed: map.seed() });
+        contradictions.0.clear();
+        state.set(AppState::Finished).unwrap();
+        break;
+      }
+      MapStatus::Failed(error) => {
+        warn!("Generating with seed {} failed: {}", map.seed(), error);
+        contradictions.0.clear();
+        for (position, directions) in map.contradiction_report() {
+          warn!(
+            "  ({}, {}) was ruled out by its {:?} neighbours",
+            position.x, position.y, directions
+          );
+          contradictions.0.push(position);
+        }
+        retry_generation(&mut map);
+        break;
+      }
+    }
+  }
+
+  progress.send(WfcProgress {
+    collapsed: map.collapsed_count(),
+    total: map.tiles.len(),
+  });
+}
+
+/**
+ * Logs how hard the map was to generate, to help with tuning the weights.
+ */
+fn log_stats(map: Res<TileMap>, mut finished: EventReader<WfcFinished>) {
+  if finished.iter().count() == 0 {
+    return;
+  }
+
+  let stats = map.stats();
+  info!(
+    "{} collapses, {} propagations, {} backtracks and {} retries in {:?}",
+    stats.collapses, stats.propagations, stats.backtracks, stats.retries, stats.elapsed
+  );
+  for position in map.softened_cells() {
+    warn!(
+      "  ({}, {}) kept contradicting and was collapsed against the rules",
+      position.x, position.y
+    );
+  }
+}
+
+fn generate_in_background(
+  mut map: ResMut<TileMap>,
+  config: Res<WfcConfig>,
+  pool: Res<AsyncComputeTaskPool>,
+  mut task: ResMut<GenerationTask>,
+  mut state: ResMut<State<AppState>>,
+  mut finished: EventWriter<WfcFinished>,
+) {
+  if !config.background {
+    return;
+  }
+
+  let running = match &mut task.0 {
+    Some(running) => running,
+    None => {
+      let mut generating = map.clone();
+      task.0 = Some(pool.spawn(async move {
+        while let Err(error) = generating.generate_within(BACKGROUND_BUDGET) {
+          warn!(
+            "Generating with seed {} failed: {}",
+            generating.seed(),
+            error
+          );
+          retry_generation(&mut generating);
+        }
+        generating
+      }));
+      return;
+    }
+  };
+
+  if let Some(generated) = future::block_on(future::poll_once(running)) {
+    task.0 = None;
+    *map = generated;
+    info!("Generated map with seed {}", map.seed());
+    finished.send(WfcFinished { seed: map.seed() });
+    state.set(AppState::Finished).unwrap();
+  }
+}
+
+fn load_rules(mut commands: Commands, asset_server: Res<AssetServer>) {
+  commands.insert_resource(RulesHandle(asset_server.load(RULES_PATH)));
+}
+
+/**
+ * Swaps in the rules and the tile size from the rules file whenever it's loaded or edited, and regenerates the map.
+ * Rules that don't validate fail to load, bevy logs why and the old map is kept.
+ */
+fn reload_rules(
+  mut map: ResMut<TileMap>,
+  mut config: ResMut<WfcConfig>,
+  mut task: ResMut<GenerationTask>,
+  mut state: ResMut<State<AppState>>,
+  mut events: EventReader<AssetEvent<RulesAsset>>,
+  rules: Res<Assets<RulesAsset>>,
+  handle: Res<RulesHandle>,
+) {
+  let changed = events.iter().any(|event| match event {
+    AssetEvent::Created { handle: changed } | AssetEvent::Modified { handle: changed } => {
+      *changed == handle.0
+    }
+    AssetEvent::Removed { .. } => false,
+  });
+  if !changed {
+    return;
+  }
+
+  let loaded = match rules.get(&handle.0) {
+    Some(loaded) => &loaded.0,
+    None => return,
+  };
+  if *loaded == map.rules {
+    return;
+  }
+
+  for tile_type in loaded.find_unreachable() {
+    warn!(
+      "{:?} can't have neighbours on every side with the rules from {}, it only shows up along the edges if at all",
+      tile_type, RULES_PATH
+    );
+  }
+
+  info!("Reloaded the rules from {}", RULES_PATH);
+  config.tile_size = Vec2::new(loaded.tile_width, loaded.tile_height);
+  config.tileset_columns = loaded.tileset_columns;
+  map.rules = loaded.clone();
+  task.0 = None;
+  start_generation(&mut map);
+  if *state.current() != AppState::Generating {
+    state.set(AppState::Generating).unwrap();
+  }
 }
 
 fn rebuild_map(
   mut map: ResMut<TileMap>,
+  mut task: ResMut<GenerationTask>,
+  mut undo_stack: ResMut<UndoStack>,
   mut regen_key: ResMut<RegenKey>,
+  mut state: ResMut<State<AppState>>,
   keys: Res<Input<KeyCode>>,
 ) {
   let r_pressed = keys.just_pressed(KeyCode::R);
 
   if !regen_key.pressed {
     if r_pressed {
-      map.clear();
-      map.generate();
+      // Dropping a background task cancels it, a new one is started from the reseeded map.
+      task.0 = None;
+      undo_stack.push(map.snapshot());
+      start_generation(&mut map);
+      if *state.current() != AppState::Generating {
+        state.set(AppState::Generating).unwrap();
+      }
       regen_key.pressed = true;
     }
   } else {
@@ -116,36 +948,303 @@ fn rebuild_map(
 
 fn draw_map(
   map: ResMut<TileMap>,
+  config: Res<WfcConfig>,
+  control: Res<WfcControl>,
   mut commands: Commands,
   asset_server: Res<AssetServer>,
   old_tiles_q: Query<Entity, With<Tile>>,
 ) {
-  let should_redraw = map.is_changed();
+  let should_redraw = map.is_changed() || control.is_changed();
 
   if !should_redraw {
     return;
   };
 
-  for entity in (&old_tiles_q).iter() {
+  for entity in old_tiles_q.iter() {
+    commands.entity(entity).despawn();
+  }
+
+  for (position, tile_type) in map.collapsed_tiles() {
+    let sprite = match tile_sprite(
+      &map,
+      position,
+      config.tile_size,
+      config.layer,
+      &asset_server,
+    ) {
+      Some(sprite) => sprite,
+      None => {
+        warn!("{:?} has no texture, leaving it out", tile_type);
+        continue;
+      }
+    };
+
+    commands
+      .spawn_bundle(sprite)
+      .insert(Tile)
+      .insert(wfc_tile(&map, tile_type));
+  }
+
+  if *control == WfcControl::Running {
+    return;
+  }
+
+  for (position, cell) in map.tiles.iter() {
+    if let Cell::Collapsed(_) = cell {
+      continue;
+    }
+
+    commands
+      .spawn_bundle(SpriteBundle {
+        transform: Transform::from_xyz(
+          position.x as f32 * config.tile_size.x + config.tile_size.x / 2.0,
+          position.y as f32 * config.tile_size.y + config.tile_size.y / 2.0,
+          config.layer,
+        ),
+        sprite: Sprite {
+          color: UNDECIDED,
+          custom_size: Some(config.tile_size),
+          ..default()
+        },
+        ..default()
+      })
+      .insert(Tile);
+  }
+}
+
+fn draw_layers(
+  layers: Res<WfcLayers>,
+  config: Res<WfcConfig>,
+  mut commands: Commands,
+  asset_server: Res<AssetServer>,
+  old_tiles_q: Query<Entity, With<LayerTile>>,
+) {
+  if !layers.is_changed() {
+    return;
+  }
+
+  for entity in old_tiles_q.iter() {
     commands.entity(entity).despawn();
   }
 
-  for (position, tile) in map.tiles.iter() {
-    match tile {
-      wfc::cell::Cell::Superposition(_) => continue,
-      wfc::cell::Cell::Collapsed(tile_type) => {
-        commands
-          .spawn_bundle(SpriteBundle {
-            texture: asset_server.load(&wfc::tile_type::TileType::get_texture(tile_type)),
-            transform: Transform::from_xyz(
-              position.x as f32 * 32.0 + 16.0,
-              position.y as f32 * 32.0 + 16.0,
-              0.0,
-            ),
-            ..default()
-          })
-          .insert(Tile);
+  for (index, layer) in layers.0.iter().enumerate() {
+    for (position, tile_type) in layer.map.collapsed_tiles() {
+      if layer.empty.as_ref() == Some(tile_type) {
+        continue;
       }
+
+      let sprite = match tile_sprite(
+        &layer.map,
+        position,
+        config.tile_size,
+        layer.layer,
+        &asset_server,
+      ) {
+        Some(sprite) => sprite,
+        None => {
+          warn!(
+            "{:?} has no texture, leaving it out of layer {}",
+            tile_type, index
+          );
+          continue;
+        }
+      };
+
+      commands
+        .spawn_bundle(sprite)
+        .insert(LayerTile)
+        .insert(wfc_tile(&layer.map, tile_type));
     }
   }
 }
+
+/**
+ * The sprite of a collapsed cell at the given z, `None` if its type has no texture.
+ */
+fn tile_sprite(
+  map: &TileMap,
+  position: &Position,
+  tile_size: Vec2,
+  z: f32,
+  asset_server: &AssetServer,
+) -> Option<SpriteBundle> {
+  let (texture, turns) = map.texture_at(position)?;
+
+  // North is down on screen, so turning clockwise on the map is turning anticlockwise here.
+  let rotation = Quat::from_rotation_z(turns as f32 * std::f32::consts::FRAC_PI_2);
+  // A sprite that's turned sideways is sized the other way around so it still fills the cell.
+  let size = if turns % 2 == 1 {
+    Vec2::new(tile_size.y, tile_size.x)
+  } else {
+    tile_size
+  };
+
+  Some(SpriteBundle {
+    texture: asset_server.load(&texture),
+    transform: Transform::from_xyz(
+      position.x as f32 * tile_size.x + tile_size.x / 2.0,
+      position.y as f32 * tile_size.y + tile_size.y / 2.0,
+      z,
+    )
+    .with_rotation(rotation),
+    sprite: Sprite {
+      custom_size: Some(size),
+      ..default()
+    },
+    ..default()
+  })
+}
+
+fn wfc_tile(map: &TileMap, tile_type: &TileType) -> WfcTile {
+  WfcTile {
+    tile_type: tile_type.clone(),
+    tags: map.rules.tags.get(tile_type).cloned().unwrap_or_default(),
+    metadata: map.rules.metadata(tile_type).cloned().unwrap_or_default(),
+  }
+}
+
+/**
+ * Draws a quad over every cell in superposition, from red for the lowest entropy on the map to blue for the highest.
+ */
+fn draw_entropy(
+  map: Res<TileMap>,
+  show: Res<ShowEntropy>,
+  config: Res<WfcConfig>,
+  mut commands: Commands,
+  old_overlay_q: Query<Entity, With<EntropyOverlay>>,
+) {
+  if !map.is_changed() && !show.is_changed() {
+    return;
+  }
+
+  for entity in old_overlay_q.iter() {
+    commands.entity(entity).despawn();
+  }
+
+  if !show.0 {
+    return;
+  }
+
+  let entropies: Vec<(&Position, f64)> = map
+    .tiles
+    .keys()
+    .filter_map(|position| Some((position, map.entropy_at(position)?)))
+    .collect();
+
+  let lowest = entropies
+    .iter()
+    .map(|(_, entropy)| *entropy)
+    .fold(f64::MAX, f64::min);
+  let highest = entropies
+    .iter()
+    .map(|(_, entropy)| *entropy)
+    .fold(f64::MIN, f64::max);
+
+  for (position, entropy) in entropies {
+    let t = if highest > lowest {
+      ((entropy - lowest) / (highest - lowest)) as f32
+    } else {
+      1.0
+    };
+    let color = Vec4::from(LOW_ENTROPY).lerp(Vec4::from(HIGH_ENTROPY), t);
+
+    commands
+      .spawn_bundle(SpriteBundle {
+        transform: Transform::from_xyz(
+          position.x as f32 * config.tile_size.x + config.tile_size.x / 2.0,
+          position.y as f32 * config.tile_size.y + config.tile_size.y / 2.0,
+          config.layer + ENTROPY_LAYER,
+        ),
+        sprite: Sprite {
+          color: color.into(),
+          custom_size: Some(config.tile_size),
+          ..default()
+        },
+        ..default()
+      })
+      .insert(EntropyOverlay);
+  }
+}
+
+fn draw_contradictions(
+  contradictions: Res<Contradictions>,
+  config: Res<WfcConfig>,
+  mut commands: Commands,
+  old_markers_q: Query<Entity, With<ContradictionMarker>>,
+) {
+  if !contradictions.is_changed() {
+    return;
+  }
+
+  for entity in old_markers_q.iter() {
+    commands.entity(entity).despawn();
+  }
+
+  for position in contradictions.0.iter() {
+    commands
+      .spawn_bundle(SpriteBundle {
+        transform: Transform::from_xyz(
+          position.x as f32 * config.tile_size.x + config.tile_size.x / 2.0,
+          position.y as f32 * config.tile_size.y + config.tile_size.y / 2.0,
+          config.layer + CONTRADICTION_LAYER,
+        ),
+        sprite: Sprite {
+          color: CONTRADICTION,
+          custom_size: Some(config.tile_size),
+          ..default()
+        },
+        ..default()
+      })
+      .insert(ContradictionMarker);
+  }
+}
+
+/**
+ * Draws a line along every cell edge, cell `(x, y)` spans from `(x, y)` to `(x + 1, y + 1)` times the tile size.
+ */
+fn draw_grid(
+  show: Res<ShowGrid>,
+  config: Res<WfcConfig>,
+  mut commands: Commands,
+  old_lines_q: Query<Entity, With<GridLine>>,
+) {
+  if !show.is_changed() && !config.is_changed() {
+    return;
+  }
+
+  for entity in old_lines_q.iter() {
+    commands.entity(entity).despawn();
+  }
+
+  if !show.0 {
+    return;
+  }
+
+  let map_size = Vec2::new(config.width as f32, config.height as f32) * config.tile_size;
+  let vertical = (0..=config.width).map(|x| {
+    (
+      Vec2::new(x as f32 * config.tile_size.x, map_size.y / 2.0),
+      Vec2::new(GRID_LINE_WIDTH, map_size.y),
+    )
+  });
+  let horizontal = (0..=config.height).map(|y| {
+    (
+      Vec2::new(map_size.x / 2.0, y as f32 * config.tile_size.y),
+      Vec2::new(map_size.x, GRID_LINE_WIDTH),
+    )
+  });
+
+  for (center, size) in vertical.chain(horizontal) {
+    commands
+      .spawn_bundle(SpriteBundle {
+        transform: Transform::from_translation(center.extend(config.layer + GRID_LAYER)),
+        sprite: Sprite {
+          color: GRID,
+          custom_size: Some(size),
+          ..default()
+        },
+        ..default()
+      })
+      .insert(GridLine);
+  }
+}
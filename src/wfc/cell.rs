@@ -1,15 +1,29 @@
-use bevy::utils::HashSet;
-
-use super::tile_type::TileType;
+use super::{tile_set::TileSet, tile_type::TileType};
 
 #[derive(Clone, Debug)]
 pub enum Cell {
   Collapsed(TileType),
-  Superposition(HashSet<TileType>),
+  Superposition(TileSet),
 }
 
 impl Cell {
   pub fn new() -> Cell {
-    Cell::Superposition(HashSet::from_iter(TileType::all_types()))
+    Cell::Superposition(TileSet::all())
+  }
+
+  /**
+   * The types this cell can still be, in `TileType::all_types` order.
+   */
+  pub fn possible_types(&self) -> Vec<TileType> {
+    match self {
+      Cell::Collapsed(tile_type) => vec![tile_type.clone()],
+      Cell::Superposition(types) => types.iter().collect(),
+    }
+  }
+}
+
+impl Default for Cell {
+  fn default() -> Self {
+    Cell::new()
   }
 }
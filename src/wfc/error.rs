@@ -0,0 +1,206 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use super::{
+  tile_map::{Direction, Position},
+  tile_type::TileType,
+};
+
+#[derive(Debug, Clone)]
+pub enum WfcError {
+  /**
+   * No tile type fits the cell at `position`.
+   * `direction` points to the neighbour that ruled out its last options.
+   */
+  Contradiction {
+    position: Position,
+    direction: Direction,
+  },
+  /**
+   * A map was given a width or height below 1.
+   */
+  InvalidSize { width: i32, height: i32 },
+  /**
+   * A map was given a depth below 1.
+   */
+  InvalidDepth { depth: i32 },
+  /**
+   * A map was given rules that don't pass `TileRules::validate`.
+   */
+  InvalidRules(Vec<RuleError>),
+  /**
+   * `TileMap::generate_within` ran out of time, the map is left partly generated.
+   */
+  Timeout { budget: Duration },
+}
+
+impl fmt::Display for WfcError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WfcError::Contradiction {
+        position,
+        direction,
+      } => write!(
+        f,
+        "no valid tile left at ({}, {}, {}), conflicting with its {:?} neighbour",
+        position.x, position.y, position.z, direction
+      ),
+      WfcError::InvalidSize { width, height } => write!(
+        f,
+        "a map has to be at least 1 by 1 cells, not {} by {}",
+        width, height
+      ),
+      WfcError::InvalidDepth { depth } => {
+        write!(f, "a map has to be at least 1 layer deep, not {}", depth)
+      }
+      WfcError::Timeout { budget } => write!(f, "the map wasn't done after {:?}", budget),
+      WfcError::InvalidRules(errors) => write!(f, "the rules aren't valid: {}", list(errors)),
+    }
+  }
+}
+
+impl std::error::Error for WfcError {}
+
+/**
+ * A problem found by `TileRules::validate`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleError {
+  MissingAdjacency(TileType),
+  MissingWeight(TileType),
+  NegativeWeight(TileType, f32),
+}
+
+impl fmt::Display for RuleError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RuleError::MissingAdjacency(tile_type) => {
+        write!(f, "{:?} has no adjacency rule", tile_type)
+      }
+      RuleError::MissingWeight(tile_type) => write!(f, "{:?} has no weight", tile_type),
+      RuleError::NegativeWeight(tile_type, weight) => {
+        write!(f, "{:?} has a negative weight of {}", tile_type, weight)
+      }
+    }
+  }
+}
+
+impl std::error::Error for RuleError {}
+
+/**
+ * The problems on one line, for the errors that hold all of them.
+ */
+fn list(errors: &[RuleError]) -> String {
+  let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+  errors.join(", ")
+}
+
+/**
+ * A problem loading rules with `TileRules::from_json`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum RulesJsonError {
+  /**
+   * The json doesn't have the shape `TileRules::to_json` writes, with what serde ran into.
+   */
+  Malformed(String),
+  Invalid(Vec<RuleError>),
+}
+
+impl fmt::Display for RulesJsonError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RulesJsonError::Malformed(error) => write!(f, "the rules can't be read: {}", error),
+      RulesJsonError::Invalid(errors) => write!(f, "the rules aren't valid: {}", list(errors)),
+    }
+  }
+}
+
+impl std::error::Error for RulesJsonError {}
+
+impl From<serde_json::Error> for RulesJsonError {
+  fn from(error: serde_json::Error) -> Self {
+    RulesJsonError::Malformed(error.to_string())
+  }
+}
+
+/**
+ * A problem reading a rule file or one of the files it includes, see `rules_file::resolve_includes`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RulesFileError {
+  /**
+   * The file couldn't be read, with what the reader ran into.
+   */
+  Unreadable {
+    path: PathBuf,
+    error: String,
+  },
+  /**
+   * The file isn't valid JSON once its comments are taken out, or its `include` isn't a list of paths.
+   */
+  InvalidJson {
+    path: PathBuf,
+    error: String,
+  },
+  IncludesItself(PathBuf),
+}
+
+impl fmt::Display for RulesFileError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RulesFileError::Unreadable { path, error } => {
+        write!(f, "couldn't read {}: {}", path.display(), error)
+      }
+      RulesFileError::InvalidJson { path, error } => {
+        write!(f, "{} isn't a valid rule file: {}", path.display(), error)
+      }
+      RulesFileError::IncludesItself(path) => write!(f, "{} includes itself", path.display()),
+    }
+  }
+}
+
+impl std::error::Error for RulesFileError {}
+
+/**
+ * A problem reading a map made in Tiled, see `wfc::tiled`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TiledError {
+  MissingLayer(String),
+  /**
+   * The layer isn't saved as CSV, other encodings aren't supported.
+   */
+  NotCsv(String),
+  InvalidGid(String),
+  UnknownGid {
+    gid: u32,
+    x: usize,
+    y: usize,
+  },
+  /**
+   * The map isn't well formed XML, with what the parser ran into.
+   */
+  InvalidXml(String),
+}
+
+impl fmt::Display for TiledError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TiledError::MissingLayer(name) => write!(f, "the map has no layer called {}", name),
+      TiledError::NotCsv(name) => write!(f, "layer {} isn't saved as CSV", name),
+      TiledError::InvalidGid(value) => write!(f, "{} is not a tile id", value),
+      TiledError::UnknownGid { gid, x, y } => {
+        write!(f, "tile id {} at ({}, {}) has no tile type", gid, x, y)
+      }
+      TiledError::InvalidXml(error) => write!(f, "the map isn't valid XML: {}", error),
+    }
+  }
+}
+
+impl std::error::Error for TiledError {}
+
+impl From<quick_xml::Error> for TiledError {
+  fn from(error: quick_xml::Error) -> Self {
+    TiledError::InvalidXml(error.to_string())
+  }
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, path::Path};
+
+use image::{imageops, ImageResult, Rgba, RgbaImage};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use super::{
+  cell::Cell,
+  tile_map::{Position, TileMap},
+  tile_type::TileType,
+};
+
+impl TileMap {
+  /**
+   * Draws the map as text, one line per row. Collapsed cells use their character in `legend`,
+   * or their type's `symbol` when it has none, cells in superposition are drawn as `?`
+   * and cells outside the map's mask as spaces. A map with a depth is drawn from its bottom layer.
+   */
+  pub fn to_ascii(&self, legend: &HashMap<TileType, char>) -> String {
+    let mut output = String::new();
+
+    for y in 0..self.height {
+      for x in 0..self.width {
+        let symbol = match self.get(&Position { x, y, z: 0 }) {
+          Some(Cell::Collapsed(tile_type)) => legend
+            .get(tile_type)
+            .cloned()
+            .unwrap_or_else(|| tile_type.symbol()),
+          Some(Cell::Superposition(_)) => '?',
+          // Outside the map's mask.
+          None => ' ',
+        };
+        output.push(symbol);
+      }
+      output.push('\n');
+    }
+
+    output
+  }
+
+  /**
+   * The texture a collapsed cell is drawn with, and the quarter turns clockwise it's drawn turned by.
+   * A texture rule matching the cell's neighbours wins. Otherwise a variant is picked using the map's seed
+   * and the cell's position, so a cell keeps its texture while the rest of the map is generated or resized.
+   * Returns `None` for cells in superposition and types without a texture.
+   */
+  pub fn texture_at(&self, position: &Position) -> Option<(String, u8)> {
+    let tile_type = match self.get(position) {
+      Some(Cell::Collapsed(tile_type)) => tile_type,
+      _ => return None,
+    };
+
+    let neighbours = self.neighbour_types(position);
+    if let Some((texture, turns)) = self.rules.texture_for(tile_type, &neighbours) {
+      return Some((texture.to_string(), turns));
+    }
+
+    // Not the index of the cell, that changes with the width.
+    let cell = (position.y as u32 as u64) << 32 | position.x as u32 as u64;
+    let mut rng = ChaCha12Rng::seed_from_u64(self.seed() ^ cell);
+
+    TileType::get_texture(tile_type, &mut rng).map(|texture| (texture, 0))
+  }
+
+  /**
+   * Renders the map to a png, using the tile textures found in `assets_dir`.
+   * Every cell is drawn as a `tile_px` square, cells without a texture are filled with `placeholder`.
+   * The image is flipped vertically so it looks the same as the map in the demo.
+   * Only the bottom layer of a map with a depth ends up in the image.
+   */
+  pub fn export_png(
+    &self,
+    assets_dir: &Path,
+    tile_px: u32,
+    placeholder: [u8; 4],
+    path: &Path,
+  ) -> ImageResult<()> {
+    let mut output = RgbaImage::from_pixel(
+      self.width as u32 * tile_px,
+      self.height as u32 * tile_px,
+      Rgba(placeholder),
+    );
+    for y in 0..self.height {
+      for x in 0..self.width {
+        let (texture, turns) = match self.texture_at(&Position { x, y, z: 0 }) {
+          Some(texture) => texture,
+          None => continue,
+        };
+
+        let texture_path = assets_dir.join(texture);
+        let mut texture = image::open(texture_path)?.to_rgba8();
+        // The image is flipped, so turning clockwise on the map is turning anticlockwise here.
+        texture = match turns {
+          1 => imageops::rotate270(&texture),
+          2 => imageops::rotate180(&texture),
+          3 => imageops::rotate90(&texture),
+          _ => texture,
+        };
+
+        if texture.width() != tile_px || texture.height() != tile_px {
+          texture = imageops::resize(&texture, tile_px, tile_px, imageops::FilterType::Nearest);
+        }
+
+        let row = (self.height - 1 - y) as u32;
+        imageops::overlay(&mut output, &texture, x as u32 * tile_px, row * tile_px);
+      }
+    }
+
+    output.save(path)
+  }
+}
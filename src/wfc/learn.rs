@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use super::{
+  tile_map::Direction,
+  tile_set::TileSet,
+  tile_type::{AdjacencyRule, TileRules, TileType, DEFAULT_TILESET_COLUMNS, DEFAULT_TILE_SIZE},
+};
+
+/**
+ * Derives tile rules from a hand painted example, indexed as `grid[y][x]`.
+ * Every pair of tiles that touch in the example becomes an allowed neighbour pair
+ * in the direction they touch in, diagonals included, and each type is weighted by how often it appears.
+ * The weights are the share of the example each type covers, so they add up to 1.
+ * Types missing from the example get no neighbours, so they are never generated.
+ */
+pub fn learn_rules_from_grid(grid: &[Vec<TileType>]) -> TileRules {
+  let empty = TileSet::empty();
+  let mut adjacency: HashMap<TileType, AdjacencyRule> = TileType::all_types()
+    .into_iter()
+    .map(|tile_type| {
+      let mut rule = AdjacencyRule::uniform(empty);
+      rule.north_east = empty;
+      rule.north_west = empty;
+      rule.south_east = empty;
+      rule.south_west = empty;
+      (tile_type, rule)
+    })
+    .collect();
+  let mut counts: HashMap<TileType, i32> = HashMap::new();
+
+  for (y, row) in grid.iter().enumerate() {
+    for (x, tile_type) in row.iter().enumerate() {
+      *counts.entry(tile_type.clone()).or_insert(0) += 1;
+
+      // Positions grow to the south in a tile map, so the next row is south of this one.
+      let next_row = grid.get(y + 1);
+      let east = row.get(x + 1).map(|tile| (Direction::East, tile));
+      let south = next_row
+        .and_then(|row| row.get(x))
+        .map(|tile| (Direction::South, tile));
+      let south_east = next_row
+        .and_then(|row| row.get(x + 1))
+        .map(|tile| (Direction::SouthEast, tile));
+      let south_west = x
+        .checked_sub(1)
+        .and_then(|x| next_row.and_then(|row| row.get(x)))
+        .map(|tile| (Direction::SouthWest, tile));
+
+      for (direction, neighbour) in [east, south, south_east, south_west].into_iter().flatten() {
+        adjacency
+          .get_mut(tile_type)
+          .unwrap()
+          .get_mut(&direction)
+          .insert(neighbour.clone());
+        adjacency
+          .get_mut(neighbour)
+          .unwrap()
+          .get_mut(&direction.opposite())
+          .insert(tile_type.clone());
+      }
+    }
+  }
+
+  let total: i32 = counts.values().sum();
+  let weights = TileType::all_types()
+    .into_iter()
+    .map(|tile_type| {
+      let count = counts.get(&tile_type).cloned().unwrap_or(0);
+      (tile_type, count as f32 / total.max(1) as f32)
+    })
+    .collect();
+
+  TileRules {
+    adjacency,
+    weights,
+    cleanup: Vec::new(),
+    tags: HashMap::new(),
+    tag_adjacency: Vec::new(),
+    merged: HashMap::new(),
+    texture_rules: Vec::new(),
+    forbidden_corners: Vec::new(),
+    directional_weights: HashMap::new(),
+    metadata: HashMap::new(),
+    tile_width: DEFAULT_TILE_SIZE,
+    tile_height: DEFAULT_TILE_SIZE,
+    tileset_columns: DEFAULT_TILESET_COLUMNS,
+  }
+}
@@ -1,3 +1,12 @@
 pub mod cell;
+pub mod error;
+pub mod export;
+pub mod learn;
+pub mod overlapping;
+pub mod rules_file;
+mod solver;
 pub mod tile_map;
+pub mod tile_set;
 pub mod tile_type;
+pub mod tiled;
+pub mod wang;
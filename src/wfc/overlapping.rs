@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use image::{Rgba, RgbaImage};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+use super::{
+  error::WfcError,
+  solver::{pick_weighted, shannon_entropy, Worklist, ENTROPY_JITTER},
+  tile_map::{Direction, Position, PropagationOrder},
+};
+
+/**
+ * The overlapping model, it generates images that look like an example image.
+ * Every `n` by `n` block of the example is a pattern, and patterns can be neighbours
+ * when they agree on the pixels they overlap.
+ * The example and the output both wrap around at the edges.
+ */
+pub struct OverlappingModel {
+  pub n: u32,
+  pub width: u32,
+  pub height: u32,
+  pub seed: u64,
+  patterns: Vec<Vec<Rgba<u8>>>,
+  weights: Vec<u32>,
+  /**
+   * `compatible[d][a][b]` is whether pattern `b` can be in the cell in direction `d` from pattern `a`.
+   */
+  compatible: Vec<Vec<Vec<bool>>>,
+}
+
+/**
+ * The directions cells are compared in, with the offset of the neighbour in that direction.
+ */
+const OFFSETS: [(Direction, i32, i32); 4] = [
+  (Direction::North, 0, -1),
+  (Direction::East, 1, 0),
+  (Direction::South, 0, 1),
+  (Direction::West, -1, 0),
+];
+
+impl OverlappingModel {
+  /**
+   * Extracts the patterns of the example image.
+   * The output is the same size as the example until `width` and `height` are changed.
+   */
+  pub fn from_image(image: &RgbaImage, n: u32) -> OverlappingModel {
+    let mut patterns: Vec<Vec<Rgba<u8>>> = Vec::new();
+    let mut weights = Vec::new();
+    let mut indices = HashMap::new();
+
+    for y in 0..image.height() {
+      for x in 0..image.width() {
+        let mut pattern = Vec::new();
+
+        for py in 0..n {
+          for px in 0..n {
+            let pixel = image.get_pixel((x + px) % image.width(), (y + py) % image.height());
+            pattern.push(*pixel);
+          }
+        }
+
+        match indices.get(&pattern) {
+          Some(&index) => weights[index] += 1,
+          None => {
+            indices.insert(pattern.clone(), patterns.len());
+            patterns.push(pattern);
+            weights.push(1);
+          }
+        }
+      }
+    }
+
+    let mut model = OverlappingModel {
+      n,
+      width: image.width(),
+      height: image.height(),
+      seed: rand::random(),
+      patterns,
+      weights,
+      compatible: Vec::new(),
+    };
+
+    model.compatible = OFFSETS
+      .iter()
+      .map(|(_, dx, dy)| {
+        (0..model.patterns.len())
+          .map(|a| {
+            (0..model.patterns.len())
+              .map(|b| model.agrees(a, b, *dx, *dy))
+              .collect()
+          })
+          .collect()
+      })
+      .collect();
+
+    model
+  }
+
+  pub fn pattern_count(&self) -> usize {
+    self.patterns.len()
+  }
+
+  /**
+   * Whether pattern `b` moved by `dx`, `dy` has the same pixels as `a` where the two overlap.
+   */
+  fn agrees(&self, a: usize, b: usize, dx: i32, dy: i32) -> bool {
+    let n = self.n as i32;
+
+    for y in 0..n {
+      for x in 0..n {
+        let (bx, by) = (x - dx, y - dy);
+        if bx < 0 || by < 0 || bx >= n || by >= n {
+          continue;
+        }
+
+        if self.patterns[a][(y * n + x) as usize] != self.patterns[b][(by * n + bx) as usize] {
+          return false;
+        }
+      }
+    }
+
+    true
+  }
+
+  /**
+   * Generates an image of `width` by `height` pixels from `seed`.
+   * Each pixel is the top left pixel of the pattern its cell collapsed to.
+   */
+  pub fn collapse(&self) -> Result<RgbaImage, WfcError> {
+    let mut rng = ChaCha12Rng::seed_from_u64(self.seed);
+    let mut wave = vec![vec![true; self.patterns.len()]; (self.width * self.height) as usize];
+
+    while let Some(cell) = self.find_lowest_entropy(&wave, &mut rng) {
+      let pattern = self.random_pattern(&wave[cell], &mut rng);
+
+      for (index, possible) in wave[cell].iter_mut().enumerate() {
+        *possible = index == pattern;
+      }
+
+      self.propagate(&mut wave, cell)?;
+    }
+
+    let mut output = RgbaImage::new(self.width, self.height);
+
+    for (cell, patterns) in wave.iter().enumerate() {
+      let pattern = patterns.iter().position(|possible| *possible).unwrap();
+      let (x, y) = (cell as u32 % self.width, cell as u32 / self.width);
+      output.put_pixel(x, y, self.patterns[pattern][0]);
+    }
+
+    Ok(output)
+  }
+
+  /**
+   * Shannon entropy of a cell, based on the weights of its remaining patterns.
+   */
+  fn calculate_entropy(&self, patterns: &[bool]) -> f64 {
+    shannon_entropy(self.possible_weights(patterns).map(|(_, weight)| weight))
+  }
+
+  /**
+   * The patterns a cell can still be, with their weights.
+   */
+  fn possible_weights<'a>(
+    &'a self,
+    patterns: &'a [bool],
+  ) -> impl Iterator<Item = (usize, f64)> + 'a {
+    (0..patterns.len())
+      .filter(|index| patterns[*index])
+      .map(|index| (index, self.weights[index] as f64))
+  }
+
+  /**
+   * The cell with more than one pattern left that has the lowest entropy.
+   */
+  fn find_lowest_entropy(&self, wave: &[Vec<bool>], rng: &mut ChaCha12Rng) -> Option<usize> {
+    let mut lowest = None;
+    let mut lowest_entropy = f64::MAX;
+
+    for (cell, patterns) in wave.iter().enumerate() {
+      if patterns.iter().filter(|possible| **possible).count() < 2 {
+        continue;
+      }
+
+      let entropy = self.calculate_entropy(patterns) + rng.gen_range(0.0..ENTROPY_JITTER);
+      if entropy < lowest_entropy {
+        lowest_entropy = entropy;
+        lowest = Some(cell);
+      }
+    }
+
+    lowest
+  }
+
+  fn random_pattern(&self, patterns: &[bool], rng: &mut ChaCha12Rng) -> usize {
+    // Every pattern was seen at least once, so a cell with a pattern left always has a weight to pick by.
+    pick_weighted(self.possible_weights(patterns).collect(), rng)
+      .expect("a cell being collapsed has patterns left")
+  }
+
+  /**
+   * Removes the patterns that no longer fit next to the changed cell, until no more changes happen.
+   */
+  fn propagate(&self, wave: &mut [Vec<bool>], cell: usize) -> Result<(), WfcError> {
+    let mut updated_cells = Worklist::new();
+    updated_cells.extend([cell]);
+
+    while let Some(cell) = updated_cells.pop(PropagationOrder::BreadthFirst) {
+      let (x, y) = (
+        (cell as u32 % self.width) as i32,
+        (cell as u32 / self.width) as i32,
+      );
+
+      for (d, (direction, dx, dy)) in OFFSETS.iter().enumerate() {
+        let neighbour_x = (x + dx).rem_euclid(self.width as i32);
+        let neighbour_y = (y + dy).rem_euclid(self.height as i32);
+        let neighbour = (neighbour_y * self.width as i32 + neighbour_x) as usize;
+
+        let mut changed = false;
+
+        for b in 0..self.patterns.len() {
+          if !wave[neighbour][b] {
+            continue;
+          }
+
+          let supported =
+            (0..self.patterns.len()).any(|a| wave[cell][a] && self.compatible[d][a][b]);
+          if !supported {
+            wave[neighbour][b] = false;
+            changed = true;
+          }
+        }
+
+        if !wave[neighbour].iter().any(|possible| *possible) {
+          return Err(WfcError::Contradiction {
+            position: Position {
+              x: neighbour_x,
+              y: neighbour_y,
+              z: 0,
+            },
+            direction: direction.opposite(),
+          });
+        }
+
+        if changed {
+          updated_cells.extend([neighbour]);
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /**
+   * Diagonal stripes of four colours, the output wraps so its size has to be a multiple of 4.
+   */
+  fn stripes() -> RgbaImage {
+    let colours = [
+      Rgba([255, 0, 0, 255]),
+      Rgba([0, 255, 0, 255]),
+      Rgba([0, 0, 255, 255]),
+      Rgba([255, 255, 255, 255]),
+    ];
+    RgbaImage::from_fn(8, 8, |x, y| colours[((x + y) % 4) as usize])
+  }
+
+  #[test]
+  fn every_block_of_the_output_is_a_pattern() {
+    let mut model = OverlappingModel::from_image(&stripes(), 2);
+    model.width = 12;
+    model.height = 12;
+    let mut generated = 0;
+
+    for seed in 0..10 {
+      model.seed = seed;
+      let image = match model.collapse() {
+        Ok(image) => image,
+        Err(_) => continue,
+      };
+      generated += 1;
+      assert_eq!(model.collapse().unwrap(), image);
+
+      let pattern_of = |image: &RgbaImage, x: u32, y: u32| -> Vec<Rgba<u8>> {
+        (0..4)
+          .map(|i| *image.get_pixel((x + i % 2) % 12, (y + i / 2) % 12))
+          .collect()
+      };
+      for y in 0..12 {
+        for x in 0..12 {
+          assert!(model.patterns.contains(&pattern_of(&image, x, y)));
+        }
+      }
+    }
+
+    assert!(generated > 0, "no seed generated an image");
+  }
+}
@@ -0,0 +1,264 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde_json::Value;
+
+use super::error::RulesFileError;
+
+/**
+ * Removes `//` comments up to the end of their line, a `//` inside a string is kept.
+ */
+pub fn strip_comments(text: &str) -> String {
+  let mut stripped = String::with_capacity(text.len());
+  let mut chars = text.chars().peekable();
+  let mut in_string = false;
+
+  while let Some(c) = chars.next() {
+    if in_string {
+      stripped.push(c);
+      match c {
+        '\\' => stripped.extend(chars.next()),
+        '"' => in_string = false,
+        _ => {}
+      }
+    } else if c == '/' && chars.peek() == Some(&'/') {
+      // The newline stays, so errors from serde still point at the right line.
+      while chars.next_if(|c| *c != '\n').is_some() {}
+    } else {
+      in_string = c == '"';
+      stripped.push(c);
+    }
+  }
+
+  stripped
+}
+
+/**
+ * Parses a rule file with comments and takes out its `include` list.
+ * The included files still have to be read and merged in, `resolve_includes` does both.
+ */
+pub fn parse_rules_file(text: &str) -> Result<(Value, Vec<String>), serde_json::Error> {
+  let mut json: Value = serde_json::from_str(&strip_comments(text))?;
+
+  let includes = match json.as_object_mut().and_then(|json| json.remove("include")) {
+    Some(includes) => serde_json::from_value(includes)?,
+    None => Vec::new(),
+  };
+
+  Ok((json, includes))
+}
+
+/**
+ * Merges `overrides` into `base`, objects are merged key by key and anything else replaces what `base` had.
+ * So a file can change a single direction of a type's adjacency without repeating the rest.
+ */
+pub fn merge_json(base: &mut Value, overrides: Value) {
+  match (base, overrides) {
+    (Value::Object(base), Value::Object(overrides)) => {
+      for (key, value) in overrides {
+        merge_json(base.entry(key).or_insert(Value::Null), value);
+      }
+    }
+    (base, overrides) => *base = overrides,
+  }
+}
+
+/**
+ * Reads a rule file and merges the files it includes, in order, with the file itself on top.
+ * `read` gets the path of every included file, made with `include_path`, and returns its contents.
+ */
+pub fn resolve_includes<F, E>(
+  path: &Path,
+  bytes: &[u8],
+  mut read: F,
+) -> Result<Value, RulesFileError>
+where
+  F: FnMut(&Path) -> Result<Vec<u8>, E>,
+  E: std::fmt::Display,
+{
+  resolve(path, bytes, &mut read, &mut vec![path.to_path_buf()])
+}
+
+/**
+ * `chain` holds the files that led to `path`, so a file that ends up including itself is an error.
+ */
+fn resolve<F, E>(
+  path: &Path,
+  bytes: &[u8],
+  read: &mut F,
+  chain: &mut Vec<PathBuf>,
+) -> Result<Value, RulesFileError>
+where
+  F: FnMut(&Path) -> Result<Vec<u8>, E>,
+  E: std::fmt::Display,
+{
+  let invalid = |error: String| RulesFileError::InvalidJson {
+    path: path.to_path_buf(),
+    error,
+  };
+  let text = std::str::from_utf8(bytes).map_err(|error| invalid(error.to_string()))?;
+  let (json, includes) = parse_rules_file(text).map_err(|error| invalid(error.to_string()))?;
+  let mut merged = Value::Null;
+
+  for include in includes {
+    let include = include_path(path, &include);
+    if chain.contains(&include) {
+      return Err(RulesFileError::IncludesItself(include));
+    }
+
+    let bytes = read(&include).map_err(|error| RulesFileError::Unreadable {
+      path: include.clone(),
+      error: error.to_string(),
+    })?;
+    chain.push(include.clone());
+    merge_json(&mut merged, resolve(&include, &bytes, read, chain)?);
+    chain.pop();
+  }
+
+  merge_json(&mut merged, json);
+  Ok(merged)
+}
+
+/**
+ * The path of a file included from `from`, relative paths start from the folder `from` is in.
+ * `.` and `..` are folded away so a file including itself through another path is still noticed.
+ */
+pub fn include_path(from: &Path, include: &str) -> PathBuf {
+  let joined = from.parent().unwrap_or_else(|| Path::new("")).join(include);
+  let mut path = PathBuf::new();
+
+  for component in joined.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => {
+        path.pop();
+      }
+      component => path.push(component),
+    }
+  }
+
+  path
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use serde_json::json;
+
+  use super::*;
+
+  /**
+   * Resolves `rules/main.rules` from `files`, a map from path to contents.
+   */
+  fn resolve_from(files: &[(&str, &str)]) -> Result<Value, RulesFileError> {
+    let files: HashMap<PathBuf, &str> = files
+      .iter()
+      .map(|(path, text)| (PathBuf::from(path), *text))
+      .collect();
+    let main = Path::new("rules/main.rules");
+
+    resolve_includes(main, files[main].as_bytes(), |path| {
+      files
+        .get(path)
+        .map(|text| text.as_bytes().to_vec())
+        .ok_or("no such file")
+    })
+  }
+
+  #[test]
+  fn comments_are_stripped_outside_strings() {
+    let text = r#"{
+      // The wiki page the rules came from.
+      "source": "http://example.com/rules", // Not part of the url.
+      "weights": { "Grass": 2.0 }
+    }"#;
+
+    let (json, includes) = parse_rules_file(text).unwrap();
+    assert!(includes.is_empty());
+    assert_eq!(
+      json,
+      json!({ "source": "http://example.com/rules", "weights": { "Grass": 2.0 } })
+    );
+  }
+
+  #[test]
+  fn an_include_is_merged_under_the_file() {
+    let base = r#"{
+      "tileTypes": ["Grass"],
+      "adjacency": { "Grass": { "north": ["Grass"] } },
+      "weights": { "Grass": 1.0 }
+    }"#;
+    let main = r#"{
+      "include": ["base.rules"],
+      "adjacency": { "Water": { "north": ["Water"] } },
+      "weights": { "Water": 3.0 }
+    }"#;
+
+    let json = resolve_from(&[("rules/base.rules", base), ("rules/main.rules", main)]).unwrap();
+    assert_eq!(
+      json,
+      json!({
+        "tileTypes": ["Grass"],
+        "adjacency": {
+          "Grass": { "north": ["Grass"] },
+          "Water": { "north": ["Water"] },
+        },
+        "weights": { "Grass": 1.0, "Water": 3.0 },
+      })
+    );
+  }
+
+  #[test]
+  fn the_including_file_overrides_the_base() {
+    let base = r#"{
+      "adjacency": { "Grass": { "north": ["Grass"], "east": ["Grass"] } },
+      "weights": { "Grass": 1.0, "Sand": 1.0 }
+    }"#;
+    let main = r#"{
+      "include": ["./shared/../base.rules"],
+      "adjacency": { "Grass": { "north": ["Sand"] } },
+      "weights": { "Sand": 5.0 }
+    }"#;
+
+    let json = resolve_from(&[("rules/base.rules", base), ("rules/main.rules", main)]).unwrap();
+    assert_eq!(
+      json,
+      json!({
+        "adjacency": { "Grass": { "north": ["Sand"], "east": ["Grass"] } },
+        "weights": { "Grass": 1.0, "Sand": 5.0 },
+      })
+    );
+  }
+
+  #[test]
+  fn includes_can_be_nested() {
+    let common = r#"{ "weights": { "Grass": 1.0, "Sand": 1.0, "Water": 1.0 } }"#;
+    let biome = r#"{ "include": ["common.rules"], "weights": { "Sand": 2.0 } }"#;
+    let main = r#"{ "include": ["shared/biome.rules"], "weights": { "Water": 3.0 } }"#;
+
+    let json = resolve_from(&[
+      ("rules/shared/common.rules", common),
+      ("rules/shared/biome.rules", biome),
+      ("rules/main.rules", main),
+    ])
+    .unwrap();
+    assert_eq!(
+      json,
+      json!({ "weights": { "Grass": 1.0, "Sand": 2.0, "Water": 3.0 } })
+    );
+  }
+
+  #[test]
+  fn an_include_cycle_is_an_error() {
+    let other = r#"{ "include": ["main.rules"] }"#;
+    let main = r#"{ "include": ["other.rules"] }"#;
+
+    let error = resolve_from(&[("rules/other.rules", other), ("rules/main.rules", main)]);
+    assert_eq!(
+      error,
+      Err(RulesFileError::IncludesItself(PathBuf::from(
+        "rules/main.rules"
+      )))
+    );
+  }
+}
@@ -0,0 +1,101 @@
+use std::{
+  collections::{HashSet, VecDeque},
+  hash::Hash,
+};
+
+use rand::Rng;
+
+use super::tile_map::PropagationOrder;
+
+/**
+ * The most noise added to an entropy to break ties, small enough that it never reorders different entropies.
+ */
+pub(crate) const ENTROPY_JITTER: f64 = 1e-6;
+
+/**
+ * Shannon entropy of a cell from the weights of what it can still be.
+ * Weights of 0 or less can't be picked, so they don't count. With none left the entropy is 0.
+ */
+pub(crate) fn shannon_entropy(weights: impl IntoIterator<Item = f64>) -> f64 {
+  let mut sum = 0.0;
+  let mut sum_log = 0.0;
+
+  for weight in weights.into_iter().filter(|weight| *weight > 0.0) {
+    sum += weight;
+    sum_log += weight * weight.ln();
+  }
+
+  if sum == 0.0 {
+    return 0.0;
+  }
+
+  sum.ln() - sum_log / sum
+}
+
+/**
+ * Picks a weighted random item, items with a weight of 0 or less are never picked.
+ * Draws from the rng once, unless no item has a weight above 0 and `None` is returned.
+ */
+pub(crate) fn pick_weighted<T>(items: Vec<(T, f64)>, rng: &mut impl Rng) -> Option<T> {
+  let total: f64 = items.iter().map(|(_, weight)| weight.max(0.0)).sum();
+  if total <= 0.0 {
+    return None;
+  }
+
+  let mut pick = rng.gen_range(0.0..total);
+  let mut last = None;
+
+  for (item, weight) in items.into_iter().filter(|(_, weight)| *weight > 0.0) {
+    if pick < weight {
+      return Some(item);
+    }
+    pick -= weight;
+    last = Some(item);
+  }
+
+  // Rounding can leave the pick just above the last weight.
+  last
+}
+
+/**
+ * The cells propagation still has to update, a cell is only in it once at a time.
+ * A cell that's already waiting will see the latest changes when its turn comes.
+ */
+pub(crate) struct Worklist<T> {
+  queue: VecDeque<T>,
+  pending: HashSet<T>,
+  /**
+   * How many cells weren't added because they were already waiting.
+   */
+  pub(crate) skipped: usize,
+}
+
+impl<T: Clone + Eq + Hash> Worklist<T> {
+  pub(crate) fn new() -> Worklist<T> {
+    Worklist {
+      queue: VecDeque::new(),
+      pending: HashSet::new(),
+      skipped: 0,
+    }
+  }
+
+  pub(crate) fn extend(&mut self, cells: impl IntoIterator<Item = T>) {
+    for cell in cells {
+      if self.pending.insert(cell.clone()) {
+        self.queue.push_back(cell);
+      } else {
+        self.skipped += 1;
+      }
+    }
+  }
+
+  pub(crate) fn pop(&mut self, order: PropagationOrder) -> Option<T> {
+    let cell = match order {
+      PropagationOrder::BreadthFirst => self.queue.pop_front(),
+      PropagationOrder::DepthFirst => self.queue.pop_back(),
+    }?;
+    self.pending.remove(&cell);
+
+    Some(cell)
+  }
+}
@@ -1,24 +1,244 @@
-use std::collections::VecDeque;
-
-use bevy::utils::{HashMap, HashSet};
+use std::{
+  cmp::Ordering,
+  collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+  sync::mpsc::Sender,
+  time::{Duration, Instant},
+};
 
 use super::{
   cell::Cell,
-  tile_type::{TileRules, TileType},
+  error::WfcError,
+  solver::{pick_weighted, shannon_entropy, Worklist, ENTROPY_JITTER},
+  tile_set::TileSet,
+  tile_type::{NeighbourTypes, TileRules, TileType},
 };
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct Position {
   pub x: i32,
   pub y: i32,
+  /**
+   * The layer of the cell, always 0 unless the map has a depth.
+   */
+  #[serde(default)]
+  pub z: i32,
+}
+
+/**
+ * The layout of a map saved with `TileMap::to_json`.
+ */
+#[derive(Serialize, Deserialize)]
+struct SavedMap {
+  width: i32,
+  height: i32,
+  #[serde(default = "flat")]
+  depth: i32,
+  tiles: Vec<SavedTile>,
+}
+
+fn flat() -> i32 {
+  1
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedTile {
+  x: i32,
+  y: i32,
+  #[serde(default)]
+  z: i32,
+  #[serde(rename = "type")]
+  tile_type: TileType,
+}
+
+/**
+ * The collapsed cells and seed of a map, taken with `TileMap::snapshot`.
+ * Cells in superposition aren't stored, they are recomputed when the snapshot is restored.
+ */
+#[derive(Clone, Debug)]
+pub struct TileMapSnapshot {
+  cells: HashMap<Position, TileType>,
+  seed: u64,
+}
+
+/**
+ * Where a map's random number generator is, taken with `TileMap::rng_state`.
+ * Saved along with a map that's partly generated, it makes resuming the generation deterministic.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RngState {
+  pub seed: u64,
+  /**
+   * How far into the stream for the seed the generator is, in 32-bit words.
+   */
+  pub word_pos: u128,
+  pub draws: u64,
+}
+
+/**
+ * How much work the last generation took, see `TileMap::stats`.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct GenerationStats {
+  /**
+   * Cells collapsed by picking a type, including picks that were undone by backtracking.
+   */
+  pub collapses: usize,
+  /**
+   * Cells updated while propagating.
+   */
+  pub propagations: usize,
+  /**
+   * Updates left out while propagating because the cell was already waiting to be updated.
+   */
+  pub skipped_updates: usize,
+  pub backtracks: usize,
+  /**
+   * Failed attempts before this one, see `TileMap::retry_with_seed`.
+   */
+  pub retries: usize,
+  /**
+   * Time spent in `step`, the time between steps doesn't count.
+   */
+  pub elapsed: Duration,
+}
+
+/**
+ * A step of a generation recorded when `record` is enabled, see `TileMap::replay`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CollapseEvent {
+  /**
+   * A cell was collapsed, either picked at random or by `collapse_at`.
+   */
+  Collapse {
+    position: Position,
+    tile_type: TileType,
+  },
+  /**
+   * Backtracking ruled out a type for a cell.
+   */
+  Forbid {
+    position: Position,
+    tile_type: TileType,
+  },
+  /**
+   * A cell that ran out of types was collapsed to the least bad type by the contradiction policy.
+   */
+  SoftCollapse {
+    position: Position,
+    tile_type: TileType,
+  },
 }
 
-enum Direction {
+#[derive(Clone, Debug)]
+pub enum Direction {
   North,
   East,
   South,
   West,
+  NorthEast,
+  NorthWest,
+  SouthEast,
+  SouthWest,
+  /**
+   * The next layer, only maps with a depth have neighbours up and down.
+   */
+  Up,
+  Down,
+}
+
+impl Direction {
+  pub fn opposite(&self) -> Direction {
+    match self {
+      Direction::North => Direction::South,
+      Direction::East => Direction::West,
+      Direction::South => Direction::North,
+      Direction::West => Direction::East,
+      Direction::NorthEast => Direction::SouthWest,
+      Direction::NorthWest => Direction::SouthEast,
+      Direction::SouthEast => Direction::NorthWest,
+      Direction::SouthWest => Direction::NorthEast,
+      Direction::Up => Direction::Down,
+      Direction::Down => Direction::Up,
+    }
+  }
+}
+
+/**
+ * How the next cell to collapse is picked.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollapseHeuristic {
+  /**
+   * The cell with the lowest weighted entropy.
+   */
+  ShannonEntropy,
+  /**
+   * The cell with the fewest types left that can be picked.
+   */
+  FewestOptions,
+  /**
+   * The first cell in superposition in row-major order a layer at a time, handy when debugging.
+   */
+  ScanlineOrder,
+}
+
+/**
+ * What generation does when a cell runs out of types.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContradictionPolicy {
+  /**
+   * Stops right away.
+   */
+  Fail,
+  /**
+   * Undoes earlier choices until the map can continue, up to `max_backtracks` times.
+   */
+  Backtrack,
+  /**
+   * Collapses the cell to the type that breaks the fewest neighbour rules and carries on.
+   * The map always finishes, but it can have neighbours that don't fit.
+   */
+  BestEffort,
+  /**
+   * Backtracks, but a cell that has run out of types `after` times is handled like with `BestEffort`,
+   * with ties going to the type there are the fewest of so far. Gets rules that are nearly impossible
+   * past the spots that keep failing at the cost of a few broken rules, see `TileMap::softened_cells`.
+   * When there's nothing left to undo the map starts over, with the failing cell softened right away.
+   */
+  Soften { after: usize },
+}
+
+/**
+ * The order cells waiting to be updated are visited in while propagating.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropagationOrder {
+  /**
+   * Oldest first, changes spread out evenly from where they started.
+   */
+  BreadthFirst,
+  /**
+   * Newest first, each change is followed as far as it goes before the next one.
+   */
+  DepthFirst,
+}
+
+/**
+ * The shape of the cells in a map.
+ * Hex maps use offset coordinates: pointy maps shift odd rows half a cell east
+ * and flat maps shift odd columns half a cell south.
+ * Pointy cells neighbour east, west and the four diagonals, flat cells north, south and the four diagonals.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+  Square,
+  HexPointy,
+  HexFlat,
 }
 
 #[derive(Debug)]
@@ -31,48 +251,310 @@ enum Validity {
 pub enum MapStatus {
   Generating,
   Finished,
+  Failed(WfcError),
+}
+
+pub(crate) const DIRECTIONS: [Direction; 4] = [
+  Direction::North,
+  Direction::East,
+  Direction::South,
+  Direction::West,
+];
+
+pub(crate) const DIRECTIONS_WITH_DIAGONALS: [Direction; 8] = [
+  Direction::North,
+  Direction::East,
+  Direction::South,
+  Direction::West,
+  Direction::NorthEast,
+  Direction::NorthWest,
+  Direction::SouthEast,
+  Direction::SouthWest,
+];
+
+const DIRECTIONS_3D: [Direction; 6] = [
+  Direction::North,
+  Direction::East,
+  Direction::South,
+  Direction::West,
+  Direction::Up,
+  Direction::Down,
+];
+
+/**
+ * Every direction an adjacency rule has a set for.
+ */
+pub(crate) const ALL_DIRECTIONS: [Direction; 10] = [
+  Direction::North,
+  Direction::East,
+  Direction::South,
+  Direction::West,
+  Direction::NorthEast,
+  Direction::NorthWest,
+  Direction::SouthEast,
+  Direction::SouthWest,
+  Direction::Up,
+  Direction::Down,
+];
+
+const HEX_POINTY_DIRECTIONS: [Direction; 6] = [
+  Direction::East,
+  Direction::West,
+  Direction::NorthEast,
+  Direction::NorthWest,
+  Direction::SouthEast,
+  Direction::SouthWest,
+];
+
+const HEX_FLAT_DIRECTIONS: [Direction; 6] = [
+  Direction::North,
+  Direction::South,
+  Direction::NorthEast,
+  Direction::NorthWest,
+  Direction::SouthEast,
+  Direction::SouthWest,
+];
+
+const DEFAULT_MAX_BACKTRACKS: usize = 1000;
+
+/**
+ * Undo information for a random collapse.
+ * Holds the previous value of every cell that changed since the collapse was made.
+ */
+#[derive(Clone)]
+struct Decision {
+  position: Position,
+  tile_type: TileType,
+  changes: Vec<(Position, Cell)>,
+  // The length of the log before the collapse, undoing it drops everything recorded since.
+  log_len: usize,
+}
+
+/**
+ * A cell waiting to be collapsed, ordered so the lowest priority is popped first.
+ * The priority is the entropy plus a small jitter to break ties,
+ * with `growth_bias` every collapsed neighbour lowers it by the size of the jitter.
+ * Entries with the same priority pop in row-major order, a layer at a time.
+ * Entries whose entropy no longer matches the cell are stale and skipped when popped.
+ */
+#[derive(Clone)]
+struct EntropyEntry {
+  priority: f64,
+  entropy: f64,
+  position: Position,
+}
+
+impl PartialEq for EntropyEntry {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for EntropyEntry {}
+
+impl PartialOrd for EntropyEntry {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for EntropyEntry {
+  fn cmp(&self, other: &Self) -> Ordering {
+    other.priority.total_cmp(&self.priority).then_with(|| {
+      let (a, b) = (&other.position, &self.position);
+      (a.z, a.y, a.x).cmp(&(b.z, b.y, b.x))
+    })
+  }
+}
+
+/**
+ * Gives the factors to scale the weights of the types at a position by.
+ */
+pub type WeightModifier = fn(&Position) -> HashMap<TileType, f32>;
+
+/**
+ * Rules used instead of the map's own rules for the cells from `min` to `max`, both included.
+ * Lets one map hold several biomes, see `TileMap::rules_for`.
+ */
+#[derive(Clone, Debug)]
+pub struct RuleRegion {
+  pub min: Position,
+  pub max: Position,
+  pub rules: TileRules,
+}
+
+impl RuleRegion {
+  pub fn contains(&self, position: &Position) -> bool {
+    position.x >= self.min.x
+      && position.y >= self.min.y
+      && position.x <= self.max.x
+      && position.y <= self.max.y
+  }
 }
 
+#[derive(Clone)]
 pub struct TileMap {
   pub width: i32,
   pub height: i32,
+  /**
+   * The number of layers, set with `with_depth`. With more than one, cells have neighbours up and down
+   * as well as the four sides, diagonals and hex cells are only used by flat maps.
+   * Regions and rule regions reach through every layer.
+   */
+  pub depth: i32,
   pub tiles: HashMap<Position, Cell>,
   pub rules: TileRules,
+  pub max_backtracks: usize,
+  /**
+   * Makes the map tileable by treating opposite edges as neighbours.
+   * Hex maps only line up when their shifted rows or columns come in pairs.
+   */
+  pub wrap: bool,
+  /**
+   * Also constrains cells by their diagonal neighbours, only used by square maps.
+   */
+  pub diagonal: bool,
+  pub topology: Topology,
+  /**
+   * The most cells each type may be collapsed to, types without an entry are unlimited.
+   */
+  pub max_counts: HashMap<TileType, usize>,
+  /**
+   * Scales the weights of the types at a position, types missing from the returned map keep their weight.
+   * Lets parts of the map favour some types without pinning any cells.
+   */
+  pub weight_modifier: Option<WeightModifier>,
+  /**
+   * Parts of the map that use their own adjacency, weights, cleanup and forbidden corners.
+   * Texture rules always come from `rules`.
+   */
+  pub rule_regions: Vec<RuleRegion>,
+  /**
+   * The rules for the cells where rule regions overlap, the map's own rules are used when it's `None`.
+   */
+  pub transition_rules: Option<TileRules>,
+  /**
+   * Records every collapse into the log so the generation can be replayed, see `replay`.
+   */
+  pub record: bool,
+  /**
+   * Changing it takes effect the next time the map is cleared, like when it's reseeded.
+   */
+  pub heuristic: CollapseHeuristic,
+  /**
+   * Among cells that are tied for the next collapse, prefers the one with the most collapsed neighbours,
+   * so the map grows outward from what's already there instead of scattering.
+   */
+  pub growth_bias: bool,
+  /**
+   * Breaks ties between cells by row-major order instead of at random, so a seed always collapses
+   * cells in an order that's easy to follow. The types are still picked at random.
+   * Like `heuristic` it takes effect the next time the map is cleared.
+   */
+  pub deterministic_ties: bool,
+  /**
+   * Breadth first is the default, with the default rules it's a little faster on square maps.
+   */
+  pub propagation_order: PropagationOrder,
+  pub contradiction_policy: ContradictionPolicy,
+  contradictions: Vec<(Position, Vec<Direction>)>,
+  // How often each cell has run out of types since the map was cleared, for `ContradictionPolicy::Soften`.
+  stuck: HashMap<Position, usize>,
+  softened: Vec<Position>,
+  // The soft collapses of the log `replay` is redoing, propagation gives up on these cells the same way.
+  replayed_soft: HashMap<Position, TileType>,
+  stats: GenerationStats,
+  log: Vec<CollapseEvent>,
+  counts: HashMap<TileType, usize>,
+  constraints: HashMap<Position, TileType>,
+  /**
+   * The types some cells start out limited to, set with `set_allowed`
+   * or along a seam with a neighbouring chunk by `generate_adjacent`.
+   */
+  allowed: HashMap<Position, TileSet>,
+  /**
+   * Weights that replace the rules' weights for single cells, set with `bias_cell`.
+   */
+  biases: HashMap<Position, HashMap<TileType, f32>>,
+  locked: HashSet<Position>,
+  started: bool,
+  seed: u64,
+  // The same generator as `StdRng`, used directly so its position in the stream can be saved.
+  rng: ChaCha12Rng,
+  // How many values have been drawn from `rng` since it was last seeded.
+  rng_draws: u64,
+  history: Vec<Decision>,
+  backtracks: usize,
+  entropy_queue: BinaryHeap<EntropyEntry>,
+  region: Option<(Position, Position)>,
+  mask: Option<HashSet<Position>>,
+  // Gets every cell the moment it's collapsed while `generate_streaming` runs.
+  stream: Option<Sender<(Position, TileType)>>,
+  // The cells changed since the last step, only tracked while a `CollapseIter` steps the map.
+  changed: Option<Vec<Position>>,
 }
 
 impl TileMap {
-  fn get_neighbour(&self, position: &Position, direction: &Direction) -> Option<(Position, Cell)> {
-    let mut new_position = Position {
-      x: position.x,
-      y: position.y,
+  fn get_neighbour(&self, position: &Position, direction: &Direction) -> Option<(Position, &Cell)> {
+    let (dx, dy, dz) = self.offset(position, direction);
+    let new_position = Position {
+      x: position.x + dx,
+      y: position.y + dy,
+      z: position.z + dz,
     };
 
-    match direction {
-      Direction::North => new_position.y -= 1,
-      Direction::East => new_position.x += 1,
-      Direction::South => new_position.y += 1,
-      Direction::West => new_position.x -= 1,
-    }
+    let new_position = self.resolve_position(new_position)?;
+    let cell = self.tiles.get(&new_position)?;
 
-    if new_position.x < 0
-      || new_position.y < 0
-      || new_position.x >= self.width
-      || new_position.y >= self.height
-    {
+    Some((new_position, cell))
+  }
+
+  /**
+   * Maps a position onto the map.
+   * Positions past the edges wrap around when `wrap` is enabled, otherwise they are outside the map.
+   */
+  fn resolve_position(&self, position: Position) -> Option<Position> {
+    // The top and bottom layers never wrap around.
+    if position.z < 0 || position.z >= self.depth {
       return None;
     }
 
-    let cell = self.tiles.get(&new_position)?;
+    if self.wrap {
+      return Some(Position {
+        x: position.x.rem_euclid(self.width),
+        y: position.y.rem_euclid(self.height),
+        z: position.z,
+      });
+    }
+
+    if position.x < 0 || position.y < 0 || position.x >= self.width || position.y >= self.height {
+      return None;
+    }
 
-    Some((new_position, cell.clone()))
+    Some(position)
   }
 
-  fn valid_neighbour(&self, a: &TileType, b: &Cell) -> Validity {
-    let rules = self.rules.adjacency.clone();
+  /**
+   * Checks if `a` at `position` can have the cell `b` at `neighbour` as its neighbour in the given direction.
+   * Both tiles have to allow each other, see `AdjacencyRule`, each by the rules at its own position.
+   */
+  fn valid_neighbour(
+    &self,
+    position: &Position,
+    a: &TileType,
+    direction: &Direction,
+    neighbour: &Position,
+    b: &Cell,
+  ) -> Validity {
+    let (rules_a, rules_b) = (self.rules_for(position), self.rules_for(neighbour));
+    let allows = |n_type: &TileType| {
+      rules_a.allows_towards(a, direction, n_type)
+        && rules_b.allows_towards(n_type, &direction.opposite(), a)
+    };
 
     match b {
       Cell::Collapsed(n_type) => {
-        let result = rules.get(n_type).unwrap().contains(a);
+        let result = allows(n_type);
 
         if result {
           Validity::Valid
@@ -81,9 +563,7 @@ impl TileMap {
         }
       }
       Cell::Superposition(n_types) => {
-        let result = n_types
-          .iter()
-          .any(|n_type| rules.get(n_type).unwrap().contains(a));
+        let result = n_types.iter().any(|n_type| allows(&n_type));
 
         if result {
           Validity::Valid
@@ -94,12 +574,41 @@ impl TileMap {
     }
   }
 
-  fn init_tiles(width: i32, height: i32) -> HashMap<Position, Cell> {
+  /**
+   * The rules for a cell: the rules of the region it's in, the transition rules where regions overlap,
+   * or the map's own rules outside every region.
+   * Two neighbours under different rules each check the other by their own rules.
+   */
+  pub fn rules_for(&self, position: &Position) -> &TileRules {
+    let mut regions = self
+      .rule_regions
+      .iter()
+      .filter(|region| region.contains(position));
+
+    match (regions.next(), regions.next()) {
+      (None, _) => &self.rules,
+      (Some(region), None) => &region.rules,
+      (Some(_), Some(_)) => self.transition_rules.as_ref().unwrap_or(&self.rules),
+    }
+  }
+
+  fn init_tiles(
+    width: i32,
+    height: i32,
+    depth: i32,
+    mask: Option<&HashSet<Position>>,
+  ) -> HashMap<Position, Cell> {
     let mut tiles = HashMap::new();
 
-    for x in 0..width {
-      for y in 0..height {
-        tiles.insert(Position { x, y }, Cell::new());
+    for z in 0..depth {
+      for x in 0..width {
+        for y in 0..height {
+          let position = Position { x, y, z };
+
+          if mask.is_none_or(|mask| mask.contains(&position)) {
+            tiles.insert(position, Cell::new());
+          }
+        }
       }
     }
 
@@ -108,191 +617,2055 @@ impl TileMap {
   /**
    * Creates new TileMap with the given width and height.
    * The map is filled with all cells in superposition.
+   * Panics if the width or height is below 1 or the rules aren't valid, see `try_new`.
    */
   pub fn new(width: i32, height: i32, rules: TileRules) -> TileMap {
-    let tiles = TileMap::init_tiles(width, height);
+    TileMap::try_new(width, height, rules).unwrap_or_else(|error| panic!("{}", error))
+  }
+
+  /**
+   * Like `new`, but returns an error for a width or height below 1 or for rules that don't pass
+   * `TileRules::validate` instead of panicking.
+   */
+  pub fn try_new(width: i32, height: i32, rules: TileRules) -> Result<TileMap, WfcError> {
+    if width < 1 || height < 1 {
+      return Err(WfcError::InvalidSize { width, height });
+    }
+    rules.validate().map_err(WfcError::InvalidRules)?;
 
-    TileMap {
+    let tiles = TileMap::init_tiles(width, height, 1, None);
+    let seed = rand::random();
+
+    let mut map = TileMap {
       width,
       height,
+      depth: 1,
       tiles,
       rules,
+      max_backtracks: DEFAULT_MAX_BACKTRACKS,
+      wrap: false,
+      diagonal: false,
+      topology: Topology::Square,
+      max_counts: HashMap::new(),
+      weight_modifier: None,
+      rule_regions: Vec::new(),
+      transition_rules: None,
+      record: false,
+      heuristic: CollapseHeuristic::ShannonEntropy,
+      growth_bias: false,
+      deterministic_ties: false,
+      propagation_order: PropagationOrder::BreadthFirst,
+      contradiction_policy: ContradictionPolicy::Backtrack,
+      contradictions: Vec::new(),
+      stuck: HashMap::new(),
+      softened: Vec::new(),
+      replayed_soft: HashMap::new(),
+      stats: GenerationStats::default(),
+      log: Vec::new(),
+      counts: HashMap::new(),
+      constraints: HashMap::new(),
+      allowed: HashMap::new(),
+      biases: HashMap::new(),
+      locked: HashSet::new(),
+      started: false,
+      seed,
+      rng: ChaCha12Rng::seed_from_u64(seed),
+      rng_draws: 0,
+      history: Vec::new(),
+      backtracks: 0,
+      entropy_queue: BinaryHeap::new(),
+      region: None,
+      mask: None,
+      stream: None,
+      changed: None,
+    };
+
+    map.queue_all_cells();
+    Ok(map)
+  }
+
+  /**
+   * Sets a cell and keeps the count of collapsed cells per type up to date.
+   */
+  fn replace_cell(&mut self, position: Position, cell: Cell) -> Option<Cell> {
+    if let Cell::Collapsed(tile_type) = &cell {
+      *self.counts.entry(tile_type.clone()).or_insert(0) += 1;
+
+      if let Some(stream) = &self.stream {
+        // Nobody listening is fine, the map is still generated.
+        let _ = stream.send((position.clone(), tile_type.clone()));
+      }
+    }
+
+    if let Some(changed) = &mut self.changed {
+      changed.push(position.clone());
+    }
+
+    let old_cell = self.tiles.insert(position, cell);
+
+    if let Some(Cell::Collapsed(tile_type)) = &old_cell {
+      if let Some(count) = self.counts.get_mut(tile_type) {
+        *count -= 1;
+      }
     }
+
+    old_cell
   }
 
-  fn get_all_neighbours(&self, position: &Position) -> Vec<(Position, Cell)> {
-    let directions = [
-      Direction::North,
-      Direction::East,
-      Direction::South,
-      Direction::West,
-    ];
+  /**
+   * Whether the type has been collapsed to as often as `max_counts` allows.
+   */
+  fn at_max_count(&self, tile_type: &TileType) -> bool {
+    match self.max_counts.get(tile_type) {
+      Some(max) => self.counts.get(tile_type).cloned().unwrap_or(0) >= *max,
+      None => false,
+    }
+  }
 
-    let neighbours = directions
-      .iter()
-      .filter_map(|direction| self.get_neighbour(position, direction))
+  /**
+   * Cells in superposition that still allow a type that reached its maximum count.
+   */
+  fn positions_over_max_count(&self) -> Vec<Position> {
+    let full_types: TileSet = TileType::all_types()
+      .into_iter()
+      .filter(|tile_type| self.at_max_count(tile_type))
       .collect();
 
-    return neighbours;
+    if full_types.is_empty() {
+      return Vec::new();
+    }
+
+    self
+      .region_positions()
+      .into_iter()
+      .filter(|position| match self.tiles.get(position) {
+        Some(Cell::Superposition(types)) => types.iter().any(|t| full_types.contains(&t)),
+        _ => false,
+      })
+      .collect()
   }
 
   /**
-   * Try to collapse cell.
-   * Returns positions of the cells neighbours if the cell was changed in some way.
+   * Sets a cell, remembering its previous value so the latest decision can be undone.
    */
-  fn update_cell(&mut self, position: Position) -> Option<Vec<Position>> {
-    let types = match self.tiles.get(&position).unwrap() {
-      Cell::Collapsed(_) => {
-        // The cell is already collapsed, it doesn't need to update.
-        return None;
+  fn set_cell(&mut self, position: Position, cell: Cell) {
+    let collapsed = matches!(cell, Cell::Collapsed(_));
+    let old_cell = self.replace_cell(position.clone(), cell);
+
+    self.queue_entropy(&position);
+
+    if collapsed && self.growth_bias {
+      // The neighbours have one more collapsed neighbour, which moves them up the queue.
+      for neighbour in self.neighbour_positions(&position) {
+        self.queue_entropy(&neighbour);
       }
-      Cell::Superposition(tiles) => tiles.clone(),
-    };
+    }
 
-    let neighbours = self.get_all_neighbours(&position);
+    if let (Some(decision), Some(old_cell)) = (self.history.last_mut(), old_cell) {
+      decision.changes.push((position, old_cell));
+    }
+  }
 
-    let type_filter = |tile_type: &&TileType| {
-      // Fold neighgours to find out if the tiletype can exist next to its neighbours.
-      let validity = neighbours.iter().fold(Validity::Invalid, |acc, (_, item)| {
-        if let Validity::Impossible = acc {
-          // A collapsed tile next to this cell is an incompatible neighbour.
-          return Validity::Impossible;
-        }
+  /**
+   * Adds the cell to the entropy queue with its current entropy, if it is in superposition.
+   */
+  fn queue_entropy(&mut self, position: &Position) {
+    if cfg!(feature = "linear-entropy-scan") {
+      return;
+    }
 
-        match self.valid_neighbour(tile_type, item) {
-          // The neighbour has a valid tile type for this type.
-          Validity::Valid => Validity::Valid,
-          // The neighbour is in superposition but none of its possible states are valid with this type.
-          Validity::Invalid => acc,
-          // The neighbour is collapsed and its type is not a valid neighbour for this one.
-          Validity::Impossible => Validity::Impossible,
-        }
-      });
+    if let Some(entry) = self.entropy_entry(position) {
+      self.entropy_queue.push(entry);
+    }
+  }
 
-      matches!(validity, Validity::Valid)
+  fn entropy_entry(&self, position: &Position) -> Option<EntropyEntry> {
+    let types = match self.tiles.get(position) {
+      Some(Cell::Superposition(types)) => types,
+      _ => return None,
     };
 
-    let possible_types: Vec<TileType> = types.iter().filter(type_filter).cloned().collect();
+    let entropy = self.collapse_priority(position, types);
+    let growth = if self.growth_bias {
+      self.collapsed_neighbour_count(position) as f64 * ENTROPY_JITTER
+    } else {
+      0.0
+    };
 
-    if possible_types.len() == 1 {
-      self.tiles.insert(
-        position.clone(),
-        Cell::Collapsed(possible_types.first().unwrap().clone()),
-      );
+    let jitter = if self.deterministic_ties {
+      0.0
     } else {
-      let set: HashSet<TileType> = possible_types.iter().cloned().collect();
+      self.tie_jitter(position)
+    };
 
-      self
-        .tiles
-        .insert(position.clone(), Cell::Superposition(set));
-    }
+    Some(EntropyEntry {
+      priority: entropy - growth + jitter,
+      entropy,
+      position: position.clone(),
+    })
+  }
 
-    if possible_types.len() != types.len() {
-      return Some(
-        neighbours
-          .iter()
-          .filter_map(|tup: &(Position, Cell)| Some(tup.0.clone()))
-          .collect(),
-      );
-    }
+  /**
+   * The jitter that breaks ties for the cell, taken from the seed and the position instead of the
+   * random number generator. A cell queued again gets the same jitter, so rebuilding the queue
+   * doesn't change which way generation goes.
+   */
+  fn tie_jitter(&self, position: &Position) -> f64 {
+    // The layer is mixed into the seed so stacked cells don't share a jitter, it's 0 on flat maps.
+    let z = (position.z as i64 as u64).wrapping_mul(0xD6E8_FEB8_6659_FD93);
+    let hash = TileMap::chunk_seed(self.seed ^ z, position.x, position.y);
+
+    (hash >> 11) as f64 / (1u64 << 53) as f64 * ENTROPY_JITTER
+  }
 
-    return None;
+  fn collapsed_neighbour_count(&self, position: &Position) -> usize {
+    self
+      .get_all_neighbours(position)
+      .iter()
+      .filter(|(_, _, cell)| matches!(cell, Cell::Collapsed(_)))
+      .count()
   }
 
   /**
-   * A function that finds the tile with the lowest amount of possible types
-   * If multiple tiles have the same amount of possible types, it will choose one at random.
+   * Rebuilds the entropy queue from every cell, in row-major order.
    */
-  fn find_lowest_entropy(&self) -> Option<Position> {
-    let mut lowest_tiles: Vec<Position> = Vec::new();
-    let mut lowest_count = std::usize::MAX;
-    let mut rng = rand::thread_rng();
+  fn queue_all_cells(&mut self) {
+    self.entropy_queue.clear();
 
-    for (position, cell) in self.tiles.iter() {
-      match cell {
-        Cell::Collapsed(_) => continue,
-        Cell::Superposition(types) => {
-          if types.len() < lowest_count {
-            lowest_tiles = Vec::new();
-            lowest_tiles.push(position.clone());
-            lowest_count = types.len();
-          } else if types.len() == lowest_count {
-            lowest_tiles.push(position.clone());
-          }
-        }
-      }
+    for position in self.region_positions() {
+      self.queue_entropy(&position);
     }
+  }
 
-    if lowest_tiles.len() == 0 {
-      return None;
+  /**
+   * Whether the cell may change, always true unless a region is being regenerated.
+   */
+  fn in_region(&self, position: &Position) -> bool {
+    match &self.region {
+      Some((min, max)) => {
+        position.x >= min.x && position.x <= max.x && position.y >= min.y && position.y <= max.y
+      }
+      None => true,
     }
-
-    let index: usize = rng.gen_range(0..lowest_tiles.len());
-    Some(lowest_tiles.get(index)?.clone())
   }
 
   /**
-   * Collapses the cell with the lowest entropy and returns its position.
+   * Every position that may change, in row-major order a layer at a time.
    */
-  fn collapse_to_random_type(&mut self) -> Option<Position> {
-    let position = self.find_lowest_entropy()?;
-    let cell = self.tiles.get(&position).unwrap().clone();
+  fn region_positions(&self) -> Vec<Position> {
+    let mut positions = Vec::new();
 
-    match cell {
-      Cell::Collapsed(_) => panic!("Tried to collapse a collapsed cell"),
-      Cell::Superposition(types) => {
-        let type_to_collapse = TileType::random_from_set(&types, &self.rules);
+    for z in 0..self.depth {
+      for y in 0..self.height {
+        for x in 0..self.width {
+          let position = Position { x, y, z };
 
-        self
-          .tiles
-          .insert(position.clone(), Cell::Collapsed(type_to_collapse));
+          if self.in_region(&position) && self.tiles.contains_key(&position) {
+            positions.push(position);
+          }
+        }
       }
     }
 
-    Some(position)
+    positions
   }
 
-  pub fn update_and_propagate(&mut self) -> MapStatus {
-    let mut updated_positions = VecDeque::new();
+  /**
+   * How far the neighbour in the given direction is from the cell.
+   */
+  fn offset(&self, position: &Position, direction: &Direction) -> (i32, i32, i32) {
+    let (dx, dy) = match direction {
+      Direction::North => (0, -1),
+      Direction::East => (1, 0),
+      Direction::South => (0, 1),
+      Direction::West => (-1, 0),
+      Direction::NorthEast => (1, -1),
+      Direction::NorthWest => (-1, -1),
+      Direction::SouthEast => (1, 1),
+      Direction::SouthWest => (-1, 1),
+      Direction::Up => return (0, 0, 1),
+      Direction::Down => return (0, 0, -1),
+    };
 
-    match self.collapse_to_random_type() {
-      Some(position) => {
-        self
-          .get_all_neighbours(&position)
-          .iter()
-          .for_each(|(pos, _)| updated_positions.push_back(pos.clone()));
+    let (dx, dy) = match self.topology {
+      Topology::Square => (dx, dy),
+      // A shifted row or column lines up with the half of its diagonal neighbours it leans towards.
+      Topology::HexPointy if dy != 0 => {
+        let shifted = position.y.rem_euclid(2) == 1;
+        match (shifted, dx > 0) {
+          (true, true) | (false, false) => (dx, dy),
+          _ => (0, dy),
+        }
       }
-      None => return MapStatus::Finished,
-    }
-
-    while !updated_positions.is_empty() {
-      if let Some(positions) = self.update_cell(updated_positions.pop_front().expect("wtf")) {
-        for position in positions {
-          updated_positions.push_back(position);
+      Topology::HexFlat if dx != 0 => {
+        let shifted = position.x.rem_euclid(2) == 1;
+        match (shifted, dy > 0) {
+          (true, true) | (false, false) => (dx, dy),
+          _ => (dx, 0),
         }
       }
-    }
+      _ => (dx, dy),
+    };
 
-    MapStatus::Generating
+    (dx, dy, 0)
   }
 
-  pub fn generate(&mut self) {
-    loop {
-      if let MapStatus::Finished = self.update_and_propagate() {
-        break;
-      }
+  /**
+   * The directions a cell has neighbours in.
+   */
+  fn directions(&self) -> &'static [Direction] {
+    match self.topology {
+      _ if self.depth > 1 => &DIRECTIONS_3D,
+      Topology::Square if self.diagonal => &DIRECTIONS_WITH_DIAGONALS,
+      Topology::Square => &DIRECTIONS,
+      Topology::HexPointy => &HEX_POINTY_DIRECTIONS,
+      Topology::HexFlat => &HEX_FLAT_DIRECTIONS,
     }
-
-    self.remove_sand_islands();
   }
 
-  pub fn clear(&mut self) {
-    let tiles = TileMap::init_tiles(self.width, self.height);
-    self.tiles = tiles;
-  }
+  /**
+   * The neighbours of a cell that are on the map, borrowed so nothing is cloned.
+   */
+  fn get_all_neighbours(&self, position: &Position) -> Vec<(&'static Direction, Position, &Cell)> {
+    self
+      .directions()
+      .iter()
+      .filter_map(|direction| {
+        let (position, cell) = self.get_neighbour(position, direction)?;
+        Some((direction, position, cell))
+      })
+      .collect()
+  }
+
+  /**
+   * Whether the type would touch a collapsed diagonal neighbour at a corner the rules forbid.
+   * Only square maps have corners, hex cells share a side with their diagonal neighbours.
+   */
+  fn breaks_corner(&self, position: &Position, tile_type: &TileType) -> bool {
+    let rules = self.rules_for(position);
+    if rules.forbidden_corners.is_empty() || self.topology != Topology::Square {
+      return false;
+    }
+
+    DIRECTIONS_WITH_DIAGONALS[4..].iter().any(|direction| {
+      match self.get_neighbour(position, direction) {
+        Some((_, Cell::Collapsed(neighbour))) => !rules.corner_allowed(tile_type, neighbour),
+        _ => false,
+      }
+    })
+  }
+
+  /**
+   * Finds the neighbour that rules out every one of the given types.
+   * Falls back to the first neighbour when the types were ruled out by different neighbours.
+   */
+  fn conflicting_direction(&self, position: &Position, types: &TileSet) -> Direction {
+    let neighbours = self.get_all_neighbours(position);
+
+    let conflicting = neighbours.iter().find(|(direction, neighbour, cell)| {
+      types.iter().all(|tile_type| {
+        !matches!(
+          self.valid_neighbour(position, &tile_type, direction, neighbour, cell),
+          Validity::Valid
+        )
+      })
+    });
+
+    conflicting
+      .or_else(|| neighbours.first())
+      .map(|(direction, _, _)| (*direction).clone())
+      .unwrap_or(Direction::North)
+  }
+
+  /**
+   * The directions of the neighbours that rule out every one of the given types.
+   * Falls back to the neighbours that rule out at least one of them when no single neighbour rules out all.
+   */
+  fn conflicting_directions(&self, position: &Position, types: &TileSet) -> Vec<Direction> {
+    let neighbours = self.get_all_neighbours(position);
+    let rules_out =
+      |direction: &Direction, neighbour: &Position, cell: &Cell, tile_type: &TileType| {
+        !matches!(
+          self.valid_neighbour(position, tile_type, direction, neighbour, cell),
+          Validity::Valid
+        )
+      };
+
+    let directions: Vec<Direction> = neighbours
+      .iter()
+      .filter(|(direction, neighbour, cell)| {
+        types
+          .iter()
+          .all(|t| rules_out(direction, neighbour, cell, &t))
+      })
+      .map(|(direction, _, _)| (*direction).clone())
+      .collect();
+
+    if !directions.is_empty() {
+      return directions;
+    }
+
+    neighbours
+      .iter()
+      .filter(|(direction, neighbour, cell)| {
+        types
+          .iter()
+          .any(|t| rules_out(direction, neighbour, cell, &t))
+      })
+      .map(|(direction, _, _)| (*direction).clone())
+      .collect()
+  }
+
+  /**
+   * Remembers a cell that ran out of types for `contradiction_report`.
+   */
+  fn record_contradiction(&mut self, position: &Position, types: &TileSet) {
+    *self.stuck.entry(position.clone()).or_insert(0) += 1;
+
+    if self
+      .contradictions
+      .iter()
+      .any(|(recorded, _)| recorded == position)
+    {
+      return;
+    }
+
+    let directions = self.conflicting_directions(position, types);
+    self.contradictions.push((position.clone(), directions));
+  }
+
+  /**
+   * The cells that ran out of types during the last step, with the directions of the neighbours
+   * that ruled out their types. Cells without directions ran out because of weights or maximum counts.
+   * Cells show up once even when backtracking ran into them more than once.
+   */
+  pub fn contradiction_report(&self) -> Vec<(Position, Vec<Direction>)> {
+    self.contradictions.clone()
+  }
+
+  /**
+   * The cells `ContradictionPolicy::Soften` collapsed against the rules since the map was last cleared,
+   * backtracking may have undone some of them since.
+   */
+  pub fn softened_cells(&self) -> &[Position] {
+    &self.softened
+  }
+
+  /**
+   * Whether a cell that ran out of types is collapsed anyway instead of failing or backtracking.
+   */
+  fn gives_up_on(&self, position: &Position) -> bool {
+    if self.replayed_soft.contains_key(position) {
+      return true;
+    }
+
+    match self.contradiction_policy {
+      ContradictionPolicy::BestEffort => true,
+      ContradictionPolicy::Soften { after } => {
+        self.stuck.get(position).cloned().unwrap_or(0) >= after
+      }
+      _ => false,
+    }
+  }
+
+  /**
+   * The types of the set that can still be picked at a position, they have a weight, haven't reached
+   * their maximum count and fit next to the neighbours and at the corners.
+   */
+  fn possible_types(&self, position: &Position, types: &TileSet) -> TileSet {
+    let neighbours = self.get_all_neighbours(position);
+
+    let type_filter = |tile_type: &TileType| {
+      // Fold neighgours to find out if the tiletype can exist next to its neighbours.
+      let validity =
+        neighbours
+          .iter()
+          .fold(Validity::Invalid, |acc, (direction, neighbour, item)| {
+            if let Validity::Impossible = acc {
+              // A collapsed tile next to this cell is an incompatible neighbour.
+              return Validity::Impossible;
+            }
+
+            // On a wrapped map one cell wide or high a cell is its own neighbour, it has to allow itself.
+            let item = if neighbour == position {
+              Cell::Collapsed(tile_type.clone())
+            } else {
+              (*item).clone()
+            };
+
+            match self.valid_neighbour(position, tile_type, direction, neighbour, &item) {
+              // The neighbour has a valid tile type for this type.
+              Validity::Valid => Validity::Valid,
+              // The neighbour is in superposition but none of its possible states are valid with this type.
+              Validity::Invalid => acc,
+              // The neighbour is collapsed and its type is not a valid neighbour for this one.
+              Validity::Impossible => Validity::Impossible,
+            }
+          });
+
+      matches!(validity, Validity::Valid)
+    };
+
+    types
+      .iter()
+      .filter(|tile_type| {
+        self.weight_at(position, tile_type) > 0.0 && !self.at_max_count(tile_type)
+      })
+      .filter(type_filter)
+      .filter(|tile_type| !self.breaks_corner(position, tile_type))
+      .collect()
+  }
+
+  /**
+   * Try to collapse cell.
+   * Returns positions of the cells neighbours if the cell was changed in some way.
+   * Returns a contradiction if no possible types remain, the cell is left as it was.
+   */
+  pub fn update_cell(&mut self, position: Position) -> Result<Option<Vec<Position>>, WfcError> {
+    if !self.in_region(&position) {
+      // Cells outside the region that's being regenerated are fixed.
+      return Ok(None);
+    }
+
+    let types = match self.tiles.get(&position) {
+      Some(Cell::Superposition(tiles)) => *tiles,
+      // The cell is already collapsed or outside the mask, it doesn't need to update.
+      _ => return Ok(None),
+    };
+
+    let possible_types = self.possible_types(&position, &types);
+    if possible_types.is_empty() {
+      // No type fits next to the neighbours, the map has to backtrack.
+      self.record_contradiction(&position, &types);
+      let direction = self.conflicting_direction(&position, &types);
+      return Err(WfcError::Contradiction {
+        position,
+        direction,
+      });
+    }
+
+    let changed = (possible_types.len() != types.len())
+      .then(|| self.neighbour_positions(&position).into_iter().collect());
+
+    if possible_types.len() == 1 {
+      self.set_cell(
+        position.clone(),
+        Cell::Collapsed(possible_types.iter().next().unwrap()),
+      );
+    } else {
+      self.set_cell(position.clone(), Cell::Superposition(possible_types));
+    }
+
+    Ok(changed)
+  }
+
+  /**
+   * The weight of a type at a position, from the cell's bias if it has one or else from the rules.
+   */
+  fn weight_at(&self, position: &Position, tile_type: &TileType) -> f32 {
+    let rules = self.rules_for(position);
+
+    match self
+      .biases
+      .get(position)
+      .and_then(|bias| bias.get(tile_type))
+    {
+      // Merged types are never picked, not even when biased.
+      Some(weight) if !rules.merged.contains_key(tile_type) => weight.max(0.0),
+      _ => rules.weight_of(tile_type),
+    }
+  }
+
+  /**
+   * The weights of the types at a position, after the weight modifier.
+   */
+  fn weights_at(&self, position: &Position, types: &TileSet) -> Vec<(TileType, f64)> {
+    let modifiers = self.weight_modifier.map(|modifier| modifier(position));
+
+    types
+      .iter()
+      .map(|tile_type| {
+        let modifier = modifiers
+          .as_ref()
+          .and_then(|modifiers| modifiers.get(&tile_type))
+          .map_or(1.0, |modifier| *modifier as f64);
+        let weight = self.weight_at(position, &tile_type) as f64 * modifier;
+        (tile_type, weight)
+      })
+      .collect()
+  }
+
+  /**
+   * Shannon entropy of a superposition, based on the weights of its remaining types.
+   * Types with a weight of 0 can't be picked, so they don't count.
+   * A cell with only those left gets an entropy of 0, so it's picked next and fails right away.
+   */
+  fn calculate_entropy(&self, position: &Position, types: &TileSet) -> f64 {
+    shannon_entropy(
+      self
+        .weights_at(position, types)
+        .into_iter()
+        .map(|(_, weight)| weight),
+    )
+  }
+
+  /**
+   * The value the heuristic orders cells by, the cell with the lowest one is collapsed next.
+   */
+  fn collapse_priority(&self, position: &Position, types: &TileSet) -> f64 {
+    match self.heuristic {
+      CollapseHeuristic::ShannonEntropy => self.calculate_entropy(position, types),
+      CollapseHeuristic::FewestOptions => types
+        .iter()
+        .filter(|tile_type| self.weight_at(position, tile_type) > 0.0)
+        .count() as f64,
+      CollapseHeuristic::ScanlineOrder => {
+        ((position.z * self.height + position.y) * self.width + position.x) as f64
+      }
+    }
+  }
+
+  /**
+   * The entropy of a cell in superposition, with the default heuristic the lowest one is collapsed next.
+   * Returns `None` for collapsed cells and positions outside the map.
+   */
+  pub fn entropy_at(&self, position: &Position) -> Option<f64> {
+    match self.tiles.get(position)? {
+      Cell::Superposition(types) => Some(self.calculate_entropy(position, types)),
+      Cell::Collapsed(_) => None,
+    }
+  }
+
+  /**
+   * Picks a weighted random type for the cell.
+   * Returns `None` when no type in the set has a weight above 0.
+   */
+  fn random_type_at(&mut self, position: &Position, types: &TileSet) -> Option<TileType> {
+    if self.weight_modifier.is_none()
+      && self.rule_regions.is_empty()
+      && self.rules.directional_weights.is_empty()
+      && !self.biases.contains_key(position)
+    {
+      let tile_type = TileType::random_from_set(types, &self.rules, &mut self.rng);
+      if tile_type.is_some() {
+        self.rng_draws += 1;
+      }
+      return tile_type;
+    }
+
+    let mut weights = self.weights_at(position, types);
+    // Only the pick is scaled, the entropy would change whenever a neighbour collapses otherwise.
+    let directional = &self.rules_for(position).directional_weights;
+    if !directional.is_empty() {
+      let neighbours = self.neighbour_types(position);
+      for (tile_type, weight) in weights.iter_mut() {
+        if let Some(directional) = directional.get(tile_type) {
+          *weight *= directional.factor(tile_type, &neighbours) as f64;
+        }
+      }
+    }
+
+    let tile_type = pick_weighted(weights, &mut self.rng);
+    if tile_type.is_some() {
+      self.rng_draws += 1;
+    }
+    tile_type
+  }
+
+  /**
+   * A function that finds the tile with the lowest entropy.
+   * Pops the entropy queue until it finds an entry that still matches its cell,
+   * or scans every cell with the `linear-entropy-scan` feature.
+   */
+  fn find_lowest_entropy(&mut self) -> Option<Position> {
+    if cfg!(feature = "linear-entropy-scan") {
+      // The scan over every cell the queue replaced, the entry the queue would pop is the greatest.
+      return self
+        .region_positions()
+        .iter()
+        .filter_map(|position| self.entropy_entry(position))
+        .max()
+        .map(|entry| entry.position);
+    }
+
+    while let Some(entry) = self.entropy_queue.pop() {
+      if let Some(Cell::Superposition(types)) = self.tiles.get(&entry.position) {
+        if self.collapse_priority(&entry.position, types) == entry.entropy {
+          return Some(entry.position);
+        }
+      }
+    }
+
+    None
+  }
+
+  /**
+   * Collapses the cell with the lowest entropy and returns its position.
+   * The choice is recorded so it can be undone when it leads to a contradiction.
+   * Fails if none of the cell's types can be picked.
+   */
+  fn collapse_to_random_type(&mut self) -> Result<Option<Position>, WfcError> {
+    let position = match self.find_lowest_entropy() {
+      Some(position) => position,
+      None => return Ok(None),
+    };
+    let cell = self.tiles.get(&position).unwrap().clone();
+
+    match cell {
+      Cell::Collapsed(_) => panic!("Tried to collapse a collapsed cell"),
+      Cell::Superposition(types) => {
+        // The cell can be behind on its neighbours, like diagonal ones that collapse without updating it,
+        // so the pick is made from the types that still fit and never contradicts them right away.
+        let allowed = self.possible_types(&position, &types);
+        let type_to_collapse = match self.random_type_at(&position, &allowed) {
+          Some(tile_type) => tile_type,
+          None => {
+            self.record_contradiction(&position, &types);
+            return Err(WfcError::Contradiction {
+              position: position.clone(),
+              direction: self.conflicting_direction(&position, &types),
+            });
+          }
+        };
+
+        self.history.push(Decision {
+          position: position.clone(),
+          tile_type: type_to_collapse.clone(),
+          changes: Vec::new(),
+          log_len: self.log.len(),
+        });
+        self.record_event(CollapseEvent::Collapse {
+          position: position.clone(),
+          tile_type: type_to_collapse.clone(),
+        });
+        self.set_cell(position.clone(), Cell::Collapsed(type_to_collapse));
+        self.stats.collapses += 1;
+      }
+    }
+
+    Ok(Some(position))
+  }
+
+  /**
+   * Updates the cells around the given changed cells, and the cells around those, until no more changes happen.
+   * Only the cells the changes reach are visited, so it's cheap after a single edit.
+   * Once a type reaches its maximum count, every cell that still allows it is updated too.
+   * Stops at the first cell that runs out of possible types, unless the contradiction policy gives up on it.
+   */
+  pub fn propagate_from(&mut self, seeds: Vec<Position>) -> Result<(), WfcError> {
+    let mut updated_positions = Worklist::new();
+    for seed in seeds {
+      updated_positions.extend(self.neighbour_positions(&seed));
+      updated_positions.extend([seed]);
+    }
+
+    let result = self.propagate_worklist(&mut updated_positions);
+    self.stats.skipped_updates += updated_positions.skipped;
+    result
+  }
+
+  fn propagate_worklist(
+    &mut self,
+    updated_positions: &mut Worklist<Position>,
+  ) -> Result<(), WfcError> {
+    loop {
+      while let Some(position) = updated_positions.pop(self.propagation_order) {
+        self.stats.propagations += 1;
+        match self.update_cell(position) {
+          Ok(Some(positions)) => updated_positions.extend(positions),
+          Ok(None) => {}
+          Err(WfcError::Contradiction { position, .. }) if self.gives_up_on(&position) => {
+            self.collapse_least_bad(&position);
+            updated_positions.extend(self.neighbour_positions(&position));
+          }
+          Err(error) => return Err(error),
+        }
+      }
+
+      let over_max_count = self.positions_over_max_count();
+      if over_max_count.is_empty() {
+        return Ok(());
+      }
+      updated_positions.extend(over_max_count);
+    }
+  }
+
+  /**
+   * Collapses a cell to the type that the fewest of its neighbours rule out.
+   * Prefers types that can still be picked, ties go to the first type in `all_types` order,
+   * or when softening to the type with the fewest cells.
+   */
+  fn collapse_least_bad(&mut self, position: &Position) {
+    let neighbours = self.get_all_neighbours(position);
+    let broken_rules = |tile_type: &TileType| {
+      neighbours
+        .iter()
+        .filter(|(direction, neighbour, cell)| {
+          !matches!(
+            self.valid_neighbour(position, tile_type, direction, neighbour, cell),
+            Validity::Valid
+          )
+        })
+        .count()
+    };
+
+    let pickable: Vec<TileType> = TileType::all_types()
+      .into_iter()
+      .filter(|tile_type| {
+        self.weight_at(position, tile_type) > 0.0 && !self.at_max_count(tile_type)
+      })
+      .collect();
+    let candidates = if pickable.is_empty() {
+      TileType::all_types()
+    } else {
+      pickable
+    };
+
+    let softening = matches!(
+      self.contradiction_policy,
+      ContradictionPolicy::Soften { .. }
+    );
+    let tile_type = match self.replayed_soft.get(position) {
+      Some(tile_type) => tile_type.clone(),
+      None => candidates
+        .into_iter()
+        .min_by_key(|tile_type| {
+          let rarity = if softening {
+            self.count_collapsed(tile_type)
+          } else {
+            0
+          };
+          (broken_rules(tile_type), rarity)
+        })
+        .unwrap(),
+    };
+    self.record_event(CollapseEvent::SoftCollapse {
+      position: position.clone(),
+      tile_type: tile_type.clone(),
+    });
+    self.set_cell(position.clone(), Cell::Collapsed(tile_type));
+    self.stats.collapses += 1;
+
+    if softening && !self.softened.contains(position) {
+      self.softened.push(position.clone());
+    }
+  }
+
+  fn neighbour_positions(&self, position: &Position) -> VecDeque<Position> {
+    self
+      .get_all_neighbours(position)
+      .into_iter()
+      .map(|(_, position, _)| position)
+      .collect()
+  }
+
+  /**
+   * Rolls back the latest random collapse and forbids the type it chose.
+   * Keeps rolling back while forbidding that type still leads to a contradiction.
+   * Returns false when there is nothing left to undo or `max_backtracks` is reached.
+   */
+  fn backtrack(&mut self) -> bool {
+    while let Some(decision) = self.history.pop() {
+      if self.backtracks >= self.max_backtracks {
+        return false;
+      }
+      self.backtracks += 1;
+      self.stats.backtracks += 1;
+
+      self.restore_changes(decision.changes);
+      self.log.truncate(decision.log_len);
+
+      let mut types = match self.tiles.get(&decision.position) {
+        Some(Cell::Superposition(types)) => *types,
+        _ => return false,
+      };
+      types.remove(&decision.tile_type);
+
+      match types.len() {
+        // Every type of this cell has been tried, undo the decision before it.
+        0 => continue,
+        1 => {
+          let tile_type = types.iter().next().unwrap();
+          self.set_cell(decision.position.clone(), Cell::Collapsed(tile_type));
+        }
+        _ => self.set_cell(decision.position.clone(), Cell::Superposition(types)),
+      }
+      self.record_event(CollapseEvent::Forbid {
+        position: decision.position.clone(),
+        tile_type: decision.tile_type,
+      });
+
+      if self.propagate_from(vec![decision.position]).is_ok() {
+        return true;
+      }
+    }
+
+    false
+  }
+
+  fn record_event(&mut self, event: CollapseEvent) {
+    if self.record {
+      self.log.push(event);
+    }
+  }
+
+  /**
+   * The collapses recorded since the map was last cleared, only filled while `record` is enabled.
+   * Regenerated regions aren't part of it.
+   */
+  pub fn log(&self) -> &[CollapseEvent] {
+    &self.log
+  }
+
+  /**
+   * Clears the map and redoes the recorded collapses, without picking anything at random.
+   * Gives the same tiles as the recorded generation when the map has the same size, rules,
+   * constraints and locks as the one that recorded the log.
+   * Cells the contradiction policy collapsed against the rules are collapsed the same way,
+   * whatever the policy of this map is.
+   * Fails if an event doesn't fit the map.
+   */
+  pub fn replay(&mut self, log: &[CollapseEvent]) -> Result<(), WfcError> {
+    self.clear();
+    self.started = true;
+    self.replayed_soft = log
+      .iter()
+      .filter_map(|event| match event {
+        CollapseEvent::SoftCollapse {
+          position,
+          tile_type,
+        } => Some((position.clone(), tile_type.clone())),
+        _ => None,
+      })
+      .collect();
+
+    let result = self.replay_events(log);
+    self.replayed_soft.clear();
+    result?;
+
+    self.history.clear();
+    self.apply_cleanup_rules();
+    Ok(())
+  }
+
+  fn replay_events(&mut self, log: &[CollapseEvent]) -> Result<(), WfcError> {
+    self.apply_constraints()?;
+
+    for event in log {
+      match event {
+        CollapseEvent::Collapse {
+          position,
+          tile_type,
+        } => self.collapse_at(position.clone(), tile_type.clone())?,
+        CollapseEvent::Forbid {
+          position,
+          tile_type,
+        } => self.forbid(position, tile_type)?,
+        // Most soft collapses already happened while propagating an earlier event,
+        // the others were cells that had types left but none that could be picked.
+        CollapseEvent::SoftCollapse { position, .. } => {
+          if let Some(Cell::Superposition(_)) = self.tiles.get(position) {
+            self.collapse_least_bad(position);
+            self.propagate_from(vec![position.clone()])?;
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Rules out a type for a cell in superposition and propagates the change.
+   */
+  fn forbid(&mut self, position: &Position, tile_type: &TileType) -> Result<(), WfcError> {
+    let mut types = match self.tiles.get(position) {
+      Some(Cell::Superposition(types)) => *types,
+      _ => return Ok(()),
+    };
+    types.remove(tile_type);
+
+    match types.len() {
+      0 => {
+        let direction = self.conflicting_direction(position, &types);
+        return Err(WfcError::Contradiction {
+          position: position.clone(),
+          direction,
+        });
+      }
+      1 => {
+        let tile_type = types.iter().next().unwrap();
+        self.set_cell(position.clone(), Cell::Collapsed(tile_type));
+      }
+      _ => self.set_cell(position.clone(), Cell::Superposition(types)),
+    }
+
+    self.record_event(CollapseEvent::Forbid {
+      position: position.clone(),
+      tile_type: tile_type.clone(),
+    });
+    self.propagate_from(vec![position.clone()])
+  }
+
+  /**
+   * Puts back the previous value of every changed cell, newest change first.
+   */
+  fn restore_changes(&mut self, changes: Vec<(Position, Cell)>) {
+    for (position, cell) in changes.into_iter().rev() {
+      self.replace_cell(position.clone(), cell);
+      self.queue_entropy(&position);
+    }
+  }
+
+  /**
+   * Collapses a cell that is still in superposition to the given type and propagates the change.
+   * Does nothing if the cell is outside the map or already collapsed.
+   * If the type doesn't fit, the map is left as it was and the contradiction is returned.
+   */
+  pub fn collapse_at(&mut self, position: Position, tile_type: TileType) -> Result<(), WfcError> {
+    let types = match self.tiles.get(&position) {
+      Some(Cell::Superposition(types)) => *types,
+      _ => return Ok(()),
+    };
+
+    if !types.contains(&tile_type) {
+      let mut forced = TileSet::empty();
+      forced.insert(tile_type);
+      let direction = self.conflicting_direction(&position, &forced);
+      return Err(WfcError::Contradiction {
+        position,
+        direction,
+      });
+    }
+
+    self.history.push(Decision {
+      position: position.clone(),
+      tile_type: tile_type.clone(),
+      changes: Vec::new(),
+      log_len: self.log.len(),
+    });
+    self.set_cell(position.clone(), Cell::Collapsed(tile_type.clone()));
+
+    let result = self
+      .check_collapsed_neighbours(&position)
+      .and_then(|_| self.propagate_from(vec![position.clone()]));
+    let decision = self.history.pop().unwrap();
+
+    match result {
+      Ok(()) => {
+        // The forced collapse is not a choice to backtrack over, undoing the decision
+        // before it undoes this one too.
+        if let Some(previous) = self.history.last_mut() {
+          previous.changes.extend(decision.changes);
+        }
+        self.record_event(CollapseEvent::Collapse {
+          position,
+          tile_type,
+        });
+        Ok(())
+      }
+      Err(error) => {
+        self.restore_changes(decision.changes);
+        Err(error)
+      }
+    }
+  }
+
+  pub fn update_and_propagate(&mut self) -> MapStatus {
+    self.contradictions.clear();
+
+    let result = match self.collapse_to_random_type() {
+      Ok(Some(position)) => self.propagate_from(vec![position]),
+      Ok(None) => return MapStatus::Finished,
+      Err(WfcError::Contradiction { position, .. }) if self.gives_up_on(&position) => {
+        self.collapse_least_bad(&position);
+        self.propagate_from(vec![position])
+      }
+      Err(error) => Err(error),
+    };
+
+    if let Err(error) = result {
+      if self.contradiction_policy == ContradictionPolicy::Fail || !self.backtrack() {
+        if let (ContradictionPolicy::Soften { after }, WfcError::Contradiction { position, .. }) =
+          (self.contradiction_policy, &error)
+        {
+          if self.backtracks < self.max_backtracks {
+            // The cell is softened the next time it runs out of types.
+            self.stuck.insert(position.clone(), after);
+            if self.region.is_none() {
+              return self.start_over();
+            }
+            return MapStatus::Generating;
+          }
+        }
+        return MapStatus::Failed(error);
+      }
+    }
+
+    MapStatus::Generating
+  }
+
+  /**
+   * Clears the map to try again after backtracking ran out of choices to undo, keeping the stuck cells
+   * and the stats. What propagating the last forbidden type changed can't be undone, starting over
+   * instead keeps the log replayable.
+   */
+  fn start_over(&mut self) -> MapStatus {
+    let stuck = std::mem::take(&mut self.stuck);
+    let stats = self.stats.clone();
+    self.clear();
+    self.stuck = stuck;
+    self.stats = stats;
+
+    // Setting `started` here keeps the backtracks made so far counting towards `max_backtracks`.
+    self.started = true;
+    match self.apply_constraints() {
+      Ok(()) => MapStatus::Generating,
+      Err(error) => MapStatus::Failed(error),
+    }
+  }
+
+  /**
+   * Checks a collapsed cell against its collapsed neighbours.
+   */
+  fn check_collapsed_neighbours(&self, position: &Position) -> Result<(), WfcError> {
+    let tile_type = match self.tiles.get(position) {
+      Some(Cell::Collapsed(tile_type)) => tile_type,
+      _ => return Ok(()),
+    };
+
+    for direction in self.directions().iter() {
+      if let Some((neighbour, cell)) = self.get_neighbour(position, direction) {
+        if let Validity::Impossible =
+          self.valid_neighbour(position, tile_type, direction, &neighbour, cell)
+        {
+          return Err(WfcError::Contradiction {
+            position: position.clone(),
+            direction: direction.clone(),
+          });
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Checks the whole map for states propagation should never leave behind: a collapsed cell
+   * next to a collapsed type it doesn't allow, or a cell in superposition with no types left.
+   * Meant for fuzzing and debugging, the best effort contradiction policy breaks the first on purpose.
+   */
+  pub fn check_invariants(&self) -> Result<(), WfcError> {
+    for z in 0..self.depth {
+      for y in 0..self.height {
+        for x in 0..self.width {
+          let position = Position { x, y, z };
+          match self.tiles.get(&position) {
+            Some(Cell::Collapsed(_)) => self.check_collapsed_neighbours(&position)?,
+            Some(Cell::Superposition(types)) if types.is_empty() => {
+              return Err(WfcError::Contradiction {
+                direction: self.conflicting_direction(&position, types),
+                position,
+              });
+            }
+            _ => {}
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /**
+   * Only generates the cells in `mask`, like an island shaped map. Cells outside it don't exist,
+   * so the cells around them act like they're on the edge of the map.
+   * Clears the map, locks outside the mask are dropped.
+   */
+  pub fn with_mask(&mut self, mask: &HashSet<Position>) {
+    let in_bounds = |position: &&Position| {
+      position.x >= 0
+        && position.y >= 0
+        && position.x < self.width
+        && position.y < self.height
+        && position.z >= 0
+        && position.z < self.depth
+    };
+    let mask: HashSet<Position> = mask.iter().filter(in_bounds).cloned().collect();
+
+    self.locked.retain(|position| mask.contains(position));
+    self.mask = Some(mask);
+    self.clear();
+  }
+
+  /**
+   * Gives the map `depth` layers of cells, stacked on top of each other from `z` 0 up.
+   * A depth of 1 is a flat map, the default. Clears the map, locks, constraints and allowed types
+   * on layers that are gone are dropped. A depth below 1 is an error and leaves the map as it was.
+   */
+  pub fn with_depth(&mut self, depth: i32) -> Result<(), WfcError> {
+    if depth < 1 {
+      return Err(WfcError::InvalidDepth { depth });
+    }
+
+    let in_bounds = |position: &Position| position.z < depth;
+    self.locked.retain(in_bounds);
+    self.constraints.retain(|position, _| in_bounds(position));
+    self.allowed.retain(|position, _| in_bounds(position));
+    self.biases.retain(|position, _| in_bounds(position));
+    if let Some(mask) = &mut self.mask {
+      mask.retain(in_bounds);
+    }
+
+    self.depth = depth;
+    self.clear();
+    Ok(())
+  }
+
+  /**
+   * Changes the size of the map, keeping the cells that fit in both sizes as they are.
+   * New cells start in superposition and are narrowed by the cells next to them,
+   * so `generate()` only fills in the new area. Locks, constraints and allowed types
+   * that no longer fit are dropped. A masked map only has the cells in its mask, so it can't grow.
+   * Fails if the kept cells rule out everything for a new cell, or on a wrapped map when the cells
+   * that now meet across the edges don't fit, the map is resized either way.
+   * A width or height below 1 is an error and leaves the map as it was.
+   */
+  pub fn resize(&mut self, width: i32, height: i32) -> Result<(), WfcError> {
+    if width < 1 || height < 1 {
+      return Err(WfcError::InvalidSize { width, height });
+    }
+
+    let in_bounds = |position: &Position| {
+      position.x >= 0 && position.y >= 0 && position.x < width && position.y < height
+    };
+    self.locked.retain(in_bounds);
+    self.constraints.retain(|position, _| in_bounds(position));
+    self.allowed.retain(|position, _| in_bounds(position));
+    self.biases.retain(|position, _| in_bounds(position));
+    if let Some(mask) = &mut self.mask {
+      mask.retain(in_bounds);
+    }
+
+    let tiles = TileMap::init_tiles(width, height, self.depth, self.mask.as_ref());
+    let old_tiles = std::mem::replace(&mut self.tiles, tiles);
+    self.width = width;
+    self.height = height;
+    self.counts.clear();
+    // Decisions made before the resize may point at cells that are gone.
+    self.history.clear();
+
+    let mut added = Vec::new();
+    let mut edges = Vec::new();
+    for z in 0..self.depth {
+      for y in 0..height {
+        for x in 0..width {
+          let position = Position { x, y, z };
+          if !self.tiles.contains_key(&position) {
+            continue;
+          }
+
+          if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+            edges.push(position.clone());
+          }
+          match old_tiles.get(&position) {
+            Some(cell) => {
+              self.replace_cell(position, cell.clone());
+            }
+            None => added.push(position),
+          }
+        }
+      }
+    }
+
+    self.queue_all_cells();
+    if self.wrap {
+      // The edges wrap around to different cells now, so the cells along them are checked again.
+      for position in edges.iter() {
+        self.check_collapsed_neighbours(position)?;
+      }
+      added.extend(edges);
+    }
+    self.propagate_from(added)
+  }
+
+  /**
+   * Pins a cell to a tile type. Pinned cells are collapsed first when generating
+   * and survive `clear()` until `clear_constraints()` is called.
+   */
+  pub fn set_constraint(&mut self, position: Position, tile_type: TileType) {
+    self.constraints.insert(position, tile_type);
+  }
+
+  /**
+   * Pins every cell of a row to a tile type, like a band of ground along the bottom of the map.
+   * On a map with a depth the row is pinned on every layer, like the rest of these.
+   */
+  pub fn set_row_constraint(&mut self, y: i32, tile_type: TileType) {
+    for z in 0..self.depth {
+      for x in 0..self.width {
+        self.set_constraint(Position { x, y, z }, tile_type.clone());
+      }
+    }
+  }
+
+  /**
+   * Pins every cell of a column to a tile type.
+   */
+  pub fn set_column_constraint(&mut self, x: i32, tile_type: TileType) {
+    for z in 0..self.depth {
+      for y in 0..self.height {
+        self.set_constraint(Position { x, y, z }, tile_type.clone());
+      }
+    }
+  }
+
+  /**
+   * Pins every cell of a layer to a tile type, like air along the top of a map with a depth.
+   */
+  pub fn set_layer_constraint(&mut self, z: i32, tile_type: TileType) {
+    for y in 0..self.height {
+      for x in 0..self.width {
+        self.set_constraint(Position { x, y, z }, tile_type.clone());
+      }
+    }
+  }
+
+  /**
+   * Pins every cell within `thickness` cells of an edge to a tile type, like water around an island.
+   */
+  pub fn set_border(&mut self, tile_type: TileType, thickness: i32) {
+    for z in 0..self.depth {
+      for y in 0..self.height {
+        for x in 0..self.width {
+          let distance = x.min(y).min(self.width - 1 - x).min(self.height - 1 - y);
+          if distance < thickness {
+            self.set_constraint(Position { x, y, z }, tile_type.clone());
+          }
+        }
+      }
+    }
+  }
+
+  /**
+   * Limits a cell to some types without picking one, a softer constraint than `set_constraint`.
+   * The cell is narrowed and propagated from when generation starts, and keeps the limit through `clear()`.
+   */
+  pub fn set_allowed(&mut self, position: Position, allowed: TileSet) {
+    self.allowed.insert(position, allowed);
+  }
+
+  /**
+   * Picks the cell's type with these weights instead of the rules' own, types left out keep theirs.
+   * The cell still has to fit its neighbours, the weights only make its types more or less likely,
+   * though like in the rules a weight of 0 rules a type out.
+   * Works together with the weight modifier and constraints, and is kept through `clear()`.
+   */
+  pub fn bias_cell(&mut self, position: Position, weights: HashMap<TileType, f32>) {
+    self.biases.insert(position.clone(), weights);
+    // The cell's entropy changed, its old entry in the queue is skipped from now on.
+    self.queue_entropy(&position);
+  }
+
+  pub fn clear_constraints(&mut self) {
+    self.constraints.clear();
+    self.allowed.clear();
+    self.biases.clear();
+  }
+
+  /**
+   * Keeps a collapsed cell as it is through `clear()` and regeneration,
+   * until `unlock` is called. Cells in superposition can't be locked.
+   */
+  pub fn lock(&mut self, position: Position) {
+    if let Some(Cell::Collapsed(_)) = self.tiles.get(&position) {
+      self.locked.insert(position);
+    }
+  }
+
+  pub fn unlock(&mut self, position: &Position) {
+    self.locked.remove(position);
+  }
+
+  pub fn is_locked(&self, position: &Position) -> bool {
+    self.locked.contains(position)
+  }
+
+  /**
+   * Whether the cell is pinned by a constraint, a lock or an allowed set, so post-processing leaves it alone.
+   */
+  fn is_pinned(&self, position: &Position) -> bool {
+    self.constraints.contains_key(position)
+      || self.locked.contains(position)
+      || self.allowed.contains_key(position)
+  }
+
+  /**
+   * Collapses the pinned cells and propagates outward from them.
+   */
+  fn apply_constraints(&mut self) -> Result<(), WfcError> {
+    let locked = self
+      .locked
+      .iter()
+      .filter_map(|position| match self.tiles.get(position) {
+        Some(Cell::Collapsed(tile_type)) => Some((position, tile_type)),
+        _ => None,
+      });
+
+    // Pinned types win over the type a locked cell had.
+    let constraints: HashMap<Position, TileType> = locked
+      .chain(self.constraints.iter())
+      .filter(|(position, _)| self.in_region(position))
+      .map(|(position, tile_type)| (position.clone(), tile_type.clone()))
+      .collect();
+
+    let narrowed = self.narrow_allowed()?;
+    self.collapse_cells(&constraints)?;
+    self.propagate_from(narrowed)
+  }
+
+  /**
+   * Removes the types that aren't allowed from the cells with an allowed set.
+   * Returns the cells that were narrowed, in row-major order a layer at a time.
+   */
+  fn narrow_allowed(&mut self) -> Result<Vec<Position>, WfcError> {
+    let mut positions: Vec<Position> = self
+      .allowed
+      .keys()
+      .filter(|position| self.in_region(position))
+      .cloned()
+      .collect();
+    positions.sort_by_key(|position| (position.z, position.y, position.x));
+
+    let mut narrowed = Vec::new();
+
+    for position in positions {
+      let allowed = self.allowed.get(&position).unwrap();
+      let types: TileSet = match self.tiles.get(&position) {
+        Some(Cell::Superposition(types)) => types.iter().filter(|t| allowed.contains(t)).collect(),
+        _ => continue,
+      };
+
+      if types.is_empty() {
+        // Seam cells are on the edge of the map, one of their directions leads into the neighbouring chunk.
+        let direction = self
+          .directions()
+          .iter()
+          .find(|direction| self.get_neighbour(&position, direction).is_none())
+          .cloned()
+          .unwrap_or(Direction::North);
+        return Err(WfcError::Contradiction {
+          position,
+          direction,
+        });
+      }
+
+      self.set_cell(position.clone(), Cell::Superposition(types));
+      narrowed.push(position);
+    }
+
+    Ok(narrowed)
+  }
+
+  /**
+   * Creates the chunk next to this one in the given direction, with the same size, rules and settings.
+   * Only `wrap` is left off, a chunk that wraps around onto itself can't line up with its neighbours.
+   * The cells of the new chunk that touch this one only allow types that fit against this chunk's edge,
+   * so the two line up at the seam once the new chunk is generated.
+   * Hex chunks only line up when their width and height are even.
+   */
+  pub fn generate_adjacent(&self, direction: &Direction) -> TileMap {
+    let (chunk_x, chunk_y, chunk_z) = match direction {
+      Direction::North => (0, -1, 0),
+      Direction::East => (1, 0, 0),
+      Direction::South => (0, 1, 0),
+      Direction::West => (-1, 0, 0),
+      Direction::NorthEast => (1, -1, 0),
+      Direction::NorthWest => (-1, -1, 0),
+      Direction::SouthEast => (1, 1, 0),
+      Direction::SouthWest => (-1, 1, 0),
+      Direction::Up => (0, 0, 1),
+      Direction::Down => (0, 0, -1),
+    };
+
+    let mut chunk = TileMap::new(self.width, self.height, self.rules.clone());
+    chunk
+      .with_depth(self.depth)
+      .expect("this chunk has a valid depth");
+    chunk.max_backtracks = self.max_backtracks;
+    chunk.diagonal = self.diagonal;
+    chunk.topology = self.topology;
+    chunk.max_counts = self.max_counts.clone();
+    chunk.weight_modifier = self.weight_modifier;
+    chunk.rule_regions = self.rule_regions.clone();
+    chunk.transition_rules = self.transition_rules.clone();
+    chunk.record = self.record;
+    chunk.heuristic = self.heuristic;
+    chunk.growth_bias = self.growth_bias;
+    chunk.deterministic_ties = self.deterministic_ties;
+    chunk.propagation_order = self.propagation_order;
+    chunk.contradiction_policy = self.contradiction_policy;
+    // The heuristic and the tie breaking only take effect once the map is cleared.
+    chunk.clear();
+
+    let mut borders = HashMap::new();
+
+    for position in chunk.tiles.keys() {
+      let mut allowed = TileSet::all();
+      let mut on_seam = false;
+
+      for neighbour_direction in chunk.directions() {
+        let (dx, dy, dz) = chunk.offset(position, neighbour_direction);
+        // The neighbour's position in this chunk.
+        let neighbour = Position {
+          x: position.x + dx + chunk_x * self.width,
+          y: position.y + dy + chunk_y * self.height,
+          z: position.z + dz + chunk_z * self.depth,
+        };
+
+        if let Some(cell) = self.tiles.get(&neighbour) {
+          on_seam = true;
+          allowed = allowed
+            .iter()
+            .filter(|tile_type| {
+              matches!(
+                self.valid_neighbour(position, tile_type, neighbour_direction, &neighbour, cell),
+                Validity::Valid
+              )
+            })
+            .collect();
+        }
+      }
+
+      if on_seam {
+        borders.insert(position.clone(), allowed);
+      }
+    }
+
+    chunk.allowed = borders;
+    chunk
+  }
+
+  /**
+   * A seed for the chunk at the given chunk coordinates, so a world generated from one seed
+   * gets the same chunks every time no matter which order they're generated in.
+   */
+  pub fn chunk_seed(seed: u64, chunk_x: i32, chunk_y: i32) -> u64 {
+    let x = (chunk_x as i64 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let y = (chunk_y as i64 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+
+    (seed ^ x ^ y.rotate_left(31)).wrapping_mul(0x1656_67B1_9E37_79F9)
+  }
+
+  /**
+   * Collapses the given cells, checks them against each other and propagates outward from them.
+   * Positions outside the map are ignored.
+   */
+  fn collapse_cells(&mut self, cells: &HashMap<Position, TileType>) -> Result<(), WfcError> {
+    let mut positions: Vec<Position> = cells
+      .keys()
+      .filter(|position| self.tiles.contains_key(position))
+      .cloned()
+      .collect();
+    positions.sort_by_key(|position| (position.z, position.y, position.x));
+
+    for position in positions.iter() {
+      let tile_type = cells.get(position).unwrap().clone();
+      self.set_cell(position.clone(), Cell::Collapsed(tile_type));
+    }
+
+    for position in positions.iter() {
+      self.check_collapsed_neighbours(position)?;
+    }
+
+    self.propagate_from(positions)
+  }
+
+  /**
+   * Clears the map and fills in the given cells, for example from a saved map,
+   * so `generate()` can complete the rest.
+   * Fails if the loaded cells break the adjacency rules.
+   */
+  pub fn load_partial(&mut self, collapsed: HashMap<Position, TileType>) -> Result<(), WfcError> {
+    self.clear();
+    self.collapse_cells(&collapsed)
+  }
+
+  pub fn snapshot(&self) -> TileMapSnapshot {
+    TileMapSnapshot {
+      cells: self
+        .collapsed_tiles()
+        .map(|(position, tile_type)| (position.clone(), tile_type.clone()))
+        .collect(),
+      seed: self.seed,
+    }
+  }
+
+  /**
+   * Puts the map back to how it was when the snapshot was taken.
+   * Fails if the snapshot doesn't fit the map's current rules.
+   */
+  pub fn restore(&mut self, snapshot: TileMapSnapshot) -> Result<(), WfcError> {
+    self.reseed(snapshot.seed);
+    self.load_partial(snapshot.cells)
+  }
+
+  /**
+   * Runs a single collapse and its propagation.
+   * The first step collapses the pinned cells, the last one runs the cleanup rules.
+   */
+  pub fn step(&mut self) -> MapStatus {
+    let start = Instant::now();
+    let status = self.timed_step();
+    self.stats.elapsed += start.elapsed();
+
+    status
+  }
+
+  fn timed_step(&mut self) -> MapStatus {
+    if !self.started {
+      self.started = true;
+      self.backtracks = 0;
+
+      if let Err(error) = self.apply_constraints() {
+        return MapStatus::Failed(error);
+      }
+    }
+
+    let status = self.update_and_propagate();
+
+    match status {
+      MapStatus::Generating => {}
+      MapStatus::Finished => {
+        self.history.clear();
+        self.apply_cleanup_rules();
+      }
+      MapStatus::Failed(_) => self.history.clear(),
+    }
+
+    status
+  }
+
+  /**
+   * What the generation has done since the map was last cleared.
+   */
+  pub fn stats(&self) -> &GenerationStats {
+    &self.stats
+  }
+
+  /**
+   * Collapses every cell of the map, starting from the pinned cells.
+   * Fails if a contradiction is found that backtracking could not resolve.
+   */
+  pub fn generate(&mut self) -> Result<(), WfcError> {
+    loop {
+      match self.step() {
+        MapStatus::Generating => continue,
+        MapStatus::Finished => return Ok(()),
+        MapStatus::Failed(error) => return Err(error),
+      }
+    }
+  }
+
+  /**
+   * Like `generate`, but gives up with a timeout once generating has taken `budget`.
+   * The time is checked between steps, a step that backtracks a lot can run over.
+   * The map is left as it was when time ran out, calling this or `generate` again carries on from there.
+   */
+  pub fn generate_within(&mut self, budget: Duration) -> Result<(), WfcError> {
+    // Steps already time themselves for the stats, so this doesn't read the clock again.
+    let deadline = self.stats.elapsed + budget;
+
+    loop {
+      match self.step() {
+        MapStatus::Generating if self.stats.elapsed >= deadline => {
+          return Err(WfcError::Timeout { budget });
+        }
+        MapStatus::Generating => continue,
+        MapStatus::Finished => return Ok(()),
+        MapStatus::Failed(error) => return Err(error),
+      }
+    }
+  }
+
+  /**
+   * Collapses whatever is left of the map, keeping the cells that are already collapsed, like painted ones.
+   * Unlike `generate_with_seed` nothing is cleared first, the cleanup rules still run at the end.
+   * Does nothing if every cell is collapsed already.
+   */
+  pub fn finish(&mut self) -> Result<(), WfcError> {
+    if self.is_fully_collapsed() {
+      return Ok(());
+    }
+
+    self.generate()
+  }
+
+  /**
+   * Steps the map one collapse at a time as it's iterated, see `CollapseIter`.
+   */
+  pub fn collapses(&mut self) -> CollapseIter<'_> {
+    CollapseIter {
+      map: self,
+      done: false,
+    }
+  }
+
+  /**
+   * Generates the map like `generate`, sending every cell through `stream` the moment it's collapsed,
+   * so something other than bevy can draw the map as it's made.
+   * Cells that were already collapsed are sent first, in row-major order a layer at a time.
+   * A cell is sent again when backtracking or cleanup changes it, the last type sent for a cell is its final type.
+   */
+  pub fn generate_streaming(
+    &mut self,
+    stream: Sender<(Position, TileType)>,
+  ) -> Result<(), WfcError> {
+    for position in self.region_positions() {
+      if let Some(Cell::Collapsed(tile_type)) = self.tiles.get(&position) {
+        let _ = stream.send((position, tile_type.clone()));
+      }
+    }
+
+    self.stream = Some(stream);
+    let result = self.generate();
+    self.stream = None;
+
+    result
+  }
+
+  /**
+   * Rerolls the cells between `min` and `max` (inclusive) and leaves the rest of the map untouched.
+   * Cells outside the box act as fixed neighbours while the region is generated.
+   */
+  pub fn regenerate_region(&mut self, min: Position, max: Position) -> Result<(), WfcError> {
+    self.region = Some((min, max));
+    let result = self.generate_region();
+    self.region = None;
+
+    result
+  }
+
+  fn generate_region(&mut self) -> Result<(), WfcError> {
+    let positions = self.region_positions();
+
+    self.history.clear();
+    self.started = false;
+
+    for position in positions.iter() {
+      if !self.locked.contains(position) {
+        self.replace_cell(position.clone(), Cell::new());
+      }
+    }
+
+    // Constrain the region by the cells around it before collapsing anything.
+    self.propagate_from(positions)?;
+    self.queue_all_cells();
+
+    self.generate()
+  }
+
+  /**
+   * Clears the map and seeds its rng, ready to be generated or stepped.
+   */
+  pub fn reseed(&mut self, seed: u64) {
+    self.seed = seed;
+    self.rng = ChaCha12Rng::seed_from_u64(seed);
+    self.rng_draws = 0;
+    self.clear();
+  }
+
+  /**
+   * Clears the map and generates it from scratch using the given seed.
+   * The same seed, rules and dimensions always produce the same tiles.
+   */
+  pub fn generate_with_seed(&mut self, seed: u64) -> Result<(), WfcError> {
+    self.reseed(seed);
+    self.generate()
+  }
+
+  /**
+   * Generates the map from a fresh seed, reseeding and retrying when generation fails.
+   * Always makes at least one attempt and returns the last error if none succeed.
+   */
+  pub fn generate_or_retry(&mut self, attempts: usize) -> Result<(), WfcError> {
+    let mut attempt = 1;
+
+    loop {
+      self.rng_draws += 1;
+      let seed = self.rng.gen();
+      let result = if attempt == 1 {
+        self.generate_with_seed(seed)
+      } else {
+        self.retry_with_seed(seed);
+        self.generate()
+      };
+
+      if result.is_ok() || attempt >= attempts {
+        return result;
+      }
+
+      attempt += 1;
+    }
+  }
+
+  /**
+   * Generates the map `attempts` times from fresh seeds and keeps the one `score` rates highest,
+   * like the one with the most grass. Attempts that fail don't count.
+   * Returns the score of the kept map, or the last error if no attempt succeeded.
+   */
+  pub fn generate_best(
+    &mut self,
+    attempts: usize,
+    score: impl Fn(&TileMap) -> f64,
+  ) -> Result<f64, WfcError> {
+    let mut best: Option<(f64, TileMap)> = None;
+    let mut last_error = None;
+
+    for _ in 0..attempts.max(1) {
+      self.rng_draws += 1;
+      let seed = self.rng.gen();
+
+      match self.generate_with_seed(seed) {
+        Ok(()) => {
+          let score = score(self);
+          if best.as_ref().is_none_or(|(best, _)| score > *best) {
+            best = Some((score, self.clone()));
+          }
+        }
+        Err(error) => last_error = Some(error),
+      }
+    }
+
+    match best {
+      Some((score, map)) => {
+        *self = map;
+        Ok(score)
+      }
+      None => Err(last_error.unwrap()),
+    }
+  }
+
+  /**
+   * Reseeds the map after a failed attempt, like `reseed`, but counts the attempt in the stats' retries.
+   */
+  pub fn retry_with_seed(&mut self, seed: u64) {
+    let retries = self.stats.retries + 1;
+    self.reseed(seed);
+    self.stats.retries = retries;
+  }
+
+  /**
+   * The seed of the last seeded generation, or the one picked when the map was created.
+   */
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  /**
+   * How many random values generation has drawn since the map was last seeded.
+   * Two runs that draw a different number of values have gone down different paths.
+   */
+  pub fn rng_draw_count(&self) -> u64 {
+    self.rng_draws
+  }
+
+  pub fn rng_state(&self) -> RngState {
+    RngState {
+      seed: self.seed,
+      word_pos: self.rng.get_word_pos(),
+      draws: self.rng_draws,
+    }
+  }
+
+  /**
+   * Puts the random number generator back to a saved state without clearing the map.
+   * Restoring the state taken partway through a generation, onto that map or onto its cells loaded
+   * with `load_partial`, reproduces the same collapses the uninterrupted generation would have made.
+   * A map loaded with `load_partial` can't backtrack past the save though.
+   */
+  pub fn set_rng_state(&mut self, state: RngState) {
+    self.seed = state.seed;
+    self.rng = ChaCha12Rng::seed_from_u64(state.seed);
+    self.rng.set_word_pos(state.word_pos);
+    self.rng_draws = state.draws;
+    self.queue_all_cells();
+  }
+
+  pub fn get(&self, position: &Position) -> Option<&Cell> {
+    self.tiles.get(position)
+  }
+
+  /**
+   * The collapsed cells and their types, in no particular order.
+   */
+  pub fn collapsed_tiles(&self) -> impl Iterator<Item = (&Position, &TileType)> {
+    self.tiles.iter().filter_map(|(position, cell)| match cell {
+      Cell::Collapsed(tile_type) => Some((position, tile_type)),
+      Cell::Superposition(_) => None,
+    })
+  }
+
+  pub fn is_fully_collapsed(&self) -> bool {
+    self.collapsed_count() == self.tiles.len()
+  }
+
+  /**
+   * The number of cells that have been collapsed so far.
+   */
+  pub fn collapsed_count(&self) -> usize {
+    self.collapsed_tiles().count()
+  }
+
+  /**
+   * The types of the collapsed cells north, east, south and west of a cell.
+   */
+  pub fn neighbour_types(&self, position: &Position) -> NeighbourTypes {
+    let neighbour = |direction: Direction| match self.get_neighbour(position, &direction) {
+      Some((_, Cell::Collapsed(tile_type))) => Some(tile_type.clone()),
+      _ => None,
+    };
+
+    NeighbourTypes {
+      north: neighbour(Direction::North),
+      east: neighbour(Direction::East),
+      south: neighbour(Direction::South),
+      west: neighbour(Direction::West),
+    }
+  }
+
+  /**
+   * The positions of the cells collapsed to the given type, in row-major order a layer at a time.
+   */
+  pub fn cells_with_type(&self, tile_type: &TileType) -> Vec<Position> {
+    let mut positions: Vec<Position> = self
+      .collapsed_tiles()
+      .filter(|(_, collapsed)| *collapsed == tile_type)
+      .map(|(position, _)| position.clone())
+      .collect();
+    positions.sort_by_key(|position| (position.z, position.y, position.x));
+
+    positions
+  }
+
+  /**
+   * The positions of the cells that are collapsed to the given type or can still become it,
+   * in row-major order a layer at a time.
+   */
+  pub fn positions_allowing(&self, tile_type: &TileType) -> Vec<Position> {
+    let mut positions: Vec<Position> = self
+      .tiles
+      .iter()
+      .filter(|(_, cell)| match cell {
+        Cell::Collapsed(collapsed) => collapsed == tile_type,
+        Cell::Superposition(types) => types.contains(tile_type),
+      })
+      .map(|(position, _)| position.clone())
+      .collect();
+    positions.sort_by_key(|position| (position.z, position.y, position.x));
+
+    positions
+  }
+
+  /**
+   * The number of cells collapsed to the given type.
+   */
+  pub fn count_collapsed(&self, tile_type: &TileType) -> usize {
+    self.counts.get(tile_type).cloned().unwrap_or(0)
+  }
+
+  /**
+   * Saves the size of the map and the type of every collapsed cell.
+   * Cells that are still in superposition are skipped.
+   */
+  pub fn to_json(&self) -> serde_json::Value {
+    let mut tiles = Vec::new();
+
+    for z in 0..self.depth {
+      for y in 0..self.height {
+        for x in 0..self.width {
+          if let Some(Cell::Collapsed(tile_type)) = self.tiles.get(&Position { x, y, z }) {
+            tiles.push(SavedTile {
+              x,
+              y,
+              z,
+              tile_type: tile_type.clone(),
+            });
+          }
+        }
+      }
+    }
+
+    let map = SavedMap {
+      width: self.width,
+      height: self.height,
+      depth: self.depth,
+      tiles,
+    };
+
+    serde_json::to_value(map).expect("saved maps always serialize")
+  }
+
+  /**
+   * Loads a map saved with `to_json`. Cells missing from the json are left in superposition.
+   * A saved size below 1 is an error, like a malformed file.
+   */
+  pub fn from_json(
+    json: &serde_json::Value,
+    rules: TileRules,
+  ) -> Result<TileMap, serde_json::Error> {
+    let saved = SavedMap::deserialize(json)?;
+    let mut map =
+      TileMap::try_new(saved.width, saved.height, rules).map_err(serde::de::Error::custom)?;
+    map
+      .with_depth(saved.depth)
+      .map_err(serde::de::Error::custom)?;
+
+    for tile in saved.tiles {
+      let position = Position {
+        x: tile.x,
+        y: tile.y,
+        z: tile.z,
+      };
+
+      if map.tiles.contains_key(&position) {
+        map.replace_cell(position, Cell::Collapsed(tile.tile_type));
+      }
+    }
+
+    Ok(map)
+  }
+
+  /**
+   * Resets every cell that isn't locked back to superposition. Constraints and locks are kept.
+   */
+  pub fn clear(&mut self) {
+    let tiles = TileMap::init_tiles(self.width, self.height, self.depth, self.mask.as_ref());
+    let old_tiles = std::mem::replace(&mut self.tiles, tiles);
+    self.counts.clear();
+
+    for position in self.locked.clone() {
+      if let Some(cell) = old_tiles.get(&position) {
+        self.replace_cell(position, cell.clone());
+      }
+    }
+    self.history.clear();
+    self.log.clear();
+    self.contradictions.clear();
+    self.stuck.clear();
+    self.softened.clear();
+    self.stats = GenerationStats::default();
+    self.started = false;
+    self.queue_all_cells();
+  }
 
-  fn should_remove_sand(&self, position: &Position) -> bool {
-    let mut surrounding_tiles: Vec<Cell> = Vec::new();
+  fn has_surrounding(&self, position: &Position, tile_type: &TileType) -> bool {
+    if self.topology != Topology::Square || self.depth > 1 {
+      return self
+        .get_all_neighbours(position)
+        .iter()
+        .any(|(_, _, cell)| matches!(cell, Cell::Collapsed(t) if t == tile_type));
+    }
 
     for x in -1..2 {
       for y in -1..2 {
@@ -300,37 +2673,664 @@ impl TileMap {
           continue;
         }
 
-        let position = Position {
+        let position = self.resolve_position(Position {
           x: position.x + x,
           y: position.y + y,
-        };
+          z: position.z,
+        });
 
-        if let Some(tile) = self.tiles.get(&position) {
-          surrounding_tiles.push(tile.clone());
+        let tile = position.and_then(|position| self.tiles.get(&position));
+        if matches!(tile, Some(Cell::Collapsed(t)) if t == tile_type) {
+          return true;
         }
       }
     }
 
-    let should_replace = !surrounding_tiles
+    false
+  }
+
+  /**
+   * Runs the cleanup rules over the collapsed map, in order, each cell by the rules from `rules_for`.
+   * A tile without the required type in its 3x3 surroundings is replaced.
+   * Pinned cells are never replaced.
+   */
+  pub fn apply_cleanup_rules(&mut self) {
+    let rule_count = self
+      .rule_regions
       .iter()
-      .any(|cell| matches!(cell, Cell::Collapsed(TileType::Grass)));
+      .map(|region| &region.rules)
+      .chain(self.transition_rules.iter())
+      .chain([&self.rules])
+      .map(|rules| rules.cleanup.len())
+      .max()
+      .unwrap_or(0);
+
+    for index in 0..rule_count {
+      let mut cells_to_update = Vec::new();
+
+      // Row-major order, so a maximum count cuts off the same cells every time.
+      for position in self.region_positions() {
+        let rule = match self.rules_for(&position).cleanup.get(index) {
+          Some(rule) if !self.is_pinned(&position) => rule,
+          _ => continue,
+        };
+
+        if let Some(Cell::Collapsed(tile_type)) = self.tiles.get(&position) {
+          if *tile_type == rule.tile && !self.has_surrounding(&position, &rule.requires_neighbour) {
+            cells_to_update.push((position, rule.replace_with.clone()));
+          }
+        }
+      }
+
+      for (cell, replace_with) in cells_to_update {
+        // Leave the tile as it is rather than going over the replacement's maximum count.
+        if self.at_max_count(&replace_with) {
+          continue;
+        }
+        self.replace_cell(cell, Cell::Collapsed(replace_with));
+      }
+    }
+  }
+
+  /**
+   * Groups the collapsed cells with a walkable type into regions connected through their neighbours.
+   * Regions are found in row-major order.
+   */
+  pub fn walkable_components(&self, walkable: &HashSet<TileType>) -> Vec<Vec<Position>> {
+    let is_walkable = |position: &Position| matches!(self.tiles.get(position), Some(Cell::Collapsed(tile_type)) if walkable.contains(tile_type));
+
+    let mut visited = HashSet::new();
+    let mut components = Vec::new();
+
+    for start in self.region_positions() {
+      if visited.contains(&start) || !is_walkable(&start) {
+        continue;
+      }
+
+      let mut component = Vec::new();
+      let mut queue = VecDeque::from([start.clone()]);
+      visited.insert(start);
+
+      while let Some(position) = queue.pop_front() {
+        for neighbour in self.neighbour_positions(&position) {
+          if is_walkable(&neighbour) && visited.insert(neighbour.clone()) {
+            queue.push_back(neighbour);
+          }
+        }
+        component.push(position);
+      }
+
+      components.push(component);
+    }
+
+    components
+  }
+
+  /**
+   * Fills in the walkable regions that aren't connected to the largest one.
+   * Each of their cells becomes the first type that isn't walkable and fits next to its neighbours.
+   * A region is left as it is when one of its cells can't be filled.
+   * Returns the number of walkable regions that remain.
+   */
+  pub fn enforce_connectivity(&mut self, walkable: &HashSet<TileType>) -> usize {
+    let mut components = self.walkable_components(walkable);
+    // The sort is stable, so of equally large regions the first one is kept.
+    components.sort_by_key(|component| std::cmp::Reverse(component.len()));
+
+    for pocket in components.iter().skip(1) {
+      let mut changes = Vec::new();
+
+      for position in pocket {
+        let fill = if self.is_pinned(position) || !self.in_region(position) {
+          None
+        } else {
+          self.fill_type(position, walkable)
+        };
+
+        match fill {
+          Some(tile_type) => {
+            let old_cell = self.replace_cell(position.clone(), Cell::Collapsed(tile_type));
+            changes.push((position.clone(), old_cell.unwrap()));
+          }
+          None => {
+            // Filling only part of the region could split it into more regions.
+            self.restore_changes(changes);
+            break;
+          }
+        }
+      }
+    }
 
-    return should_replace;
+    self.walkable_components(walkable).len()
   }
 
-  pub fn remove_sand_islands(&mut self) {
-    let mut cells_to_update = Vec::new();
+  /**
+   * The first type that isn't walkable and fits next to the cell's neighbours.
+   */
+  fn fill_type(&self, position: &Position, walkable: &HashSet<TileType>) -> Option<TileType> {
+    let neighbours = self.get_all_neighbours(position);
+
+    TileType::all_types().into_iter().find(|tile_type| {
+      !walkable.contains(tile_type)
+        && !self.at_max_count(tile_type)
+        && neighbours.iter().all(|(direction, neighbour, cell)| {
+          !matches!(
+            self.valid_neighbour(position, tile_type, direction, neighbour, cell),
+            Validity::Impossible
+          )
+        })
+    })
+  }
+}
+
+/**
+ * Steps a map each time `next` is called and yields the cells that changed,
+ * in row-major order a layer at a time.
+ * The last step also runs the cleanup rules. When generation fails the error is yielded once,
+ * either way the iterator ends after the map stops generating.
+ */
+pub struct CollapseIter<'a> {
+  map: &'a mut TileMap,
+  done: bool,
+}
+
+impl Iterator for CollapseIter<'_> {
+  type Item = Result<Vec<Position>, WfcError>;
 
-    for (position, cell) in self.tiles.iter() {
-      if let Cell::Collapsed(TileType::Sand) = cell {
-        if self.should_remove_sand(&position) {
-          cells_to_update.push(position.clone());
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+
+    self.map.changed = Some(Vec::new());
+    let status = self.map.step();
+    let mut changed = self.map.changed.take().unwrap();
+    changed.sort_by_key(|position| (position.z, position.y, position.x));
+    changed.dedup();
+
+    match status {
+      MapStatus::Generating => Some(Ok(changed)),
+      MapStatus::Finished => {
+        self.done = true;
+        // Cleanup can still change cells once nothing is left to collapse.
+        if changed.is_empty() {
+          None
+        } else {
+          Some(Ok(changed))
         }
       }
+      MapStatus::Failed(error) => {
+        self.done = true;
+        Some(Err(error))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wfc::{error::RuleError, learn::learn_rules_from_grid, tile_type::AdjacencyRule};
+
+  /**
+   * Fails unless every cell is collapsed and every pair of neighbours fits the rules.
+   */
+  fn assert_complete(map: &TileMap) {
+    map.check_invariants().unwrap();
+    assert!(map.is_fully_collapsed());
+  }
+
+  fn collapsed(map: &TileMap) -> HashMap<Position, TileType> {
+    map
+      .collapsed_tiles()
+      .map(|(position, tile_type)| (position.clone(), tile_type.clone()))
+      .collect()
+  }
+
+  /**
+   * Grass, Water and Trees, none of which may be next to itself.
+   * Picking at random often boxes a cell in, so some seeds need backtracking to finish.
+   */
+  fn three_colour_rules() -> TileRules {
+    let colours = [TileType::Grass, TileType::Water, TileType::Trees];
+    let mut rules = TileType::default_rules();
+    rules.cleanup.clear();
+
+    for tile_type in TileType::all_types() {
+      let allowed = colours
+        .iter()
+        .filter(|other| colours.contains(&tile_type) && **other != tile_type)
+        .cloned();
+      rules.adjacency.insert(
+        tile_type.clone(),
+        AdjacencyRule::uniform(TileSet::from_iter(allowed)),
+      );
+      rules.weights.insert(tile_type, 1.0);
+    }
+
+    rules
+  }
+
+  #[test]
+  fn backtracking_recovers_from_contradictions() {
+    let mut backtracks = 0;
+
+    for seed in 0..10 {
+      let mut map = TileMap::new(9, 9, three_colour_rules());
+      map.generate_with_seed(seed).unwrap();
+
+      assert_complete(&map);
+      backtracks += map.backtracks;
+    }
+
+    assert!(backtracks > 0, "the rules never needed backtracking");
+  }
+
+  #[test]
+  fn pinned_corners_keep_their_type() {
+    let corners = [(0, 0), (11, 0), (0, 11), (11, 11)];
+
+    for seed in 0..5 {
+      let mut map = TileMap::new(12, 12, TileType::default_rules());
+      for (x, y) in corners {
+        map.set_constraint(Position { x, y, z: 0 }, TileType::Water);
+      }
+      map.generate_with_seed(seed).unwrap();
+
+      assert_complete(&map);
+      for (x, y) in corners {
+        assert!(matches!(
+          map.tiles.get(&Position { x, y, z: 0 }),
+          Some(Cell::Collapsed(TileType::Water))
+        ));
+      }
+    }
+  }
+
+  #[test]
+  fn max_count_of_zero_keeps_a_type_out() {
+    for seed in 0..10 {
+      let mut map = TileMap::new(16, 16, TileType::default_rules());
+      map.max_counts.insert(TileType::Water, 0);
+      map.generate_with_seed(seed).unwrap();
+
+      assert_complete(&map);
+      let water = map
+        .tiles
+        .values()
+        .filter(|cell| matches!(cell, Cell::Collapsed(TileType::Water)))
+        .count();
+      assert_eq!(water, 0);
+    }
+  }
+
+  #[test]
+  fn same_seed_gives_the_same_map() {
+    let mut first = None;
+
+    for _ in 0..10 {
+      let mut map = TileMap::new(20, 20, TileType::default_rules());
+      map.generate_with_seed(99).unwrap();
+
+      let tiles = collapsed(&map);
+      match &first {
+        None => first = Some(tiles),
+        Some(first) => assert_eq!(&tiles, first),
+      }
+    }
+  }
+
+  #[test]
+  fn same_seed_gives_the_same_layered_map() {
+    let mut first = None;
+
+    for _ in 0..10 {
+      let mut map = TileMap::new(8, 8, TileType::default_rules());
+      map.with_depth(3).unwrap();
+      map.set_row_constraint(0, TileType::Water);
+      map.set_allowed(
+        Position { x: 3, y: 3, z: 1 },
+        TileSet::from_iter([TileType::Grass, TileType::Sand]),
+      );
+      map.generate_with_seed(7).unwrap();
+
+      // The lists of positions have to come out in the same order too.
+      let tiles = (collapsed(&map), map.cells_with_type(&TileType::Grass));
+      match &first {
+        None => first = Some(tiles),
+        Some(first) => assert_eq!(&tiles, first),
+      }
+    }
+  }
+
+  #[test]
+  fn isolated_grass_is_filled_in() {
+    let isolated = Position { x: 5, y: 2, z: 0 };
+    let mut cells = HashMap::new();
+    for y in 0..6 {
+      for x in 0..6 {
+        let tile_type = if x < 3 {
+          TileType::Grass
+        } else {
+          TileType::Trees
+        };
+        cells.insert(Position { x, y, z: 0 }, tile_type);
+      }
+    }
+    cells.insert(isolated.clone(), TileType::Grass);
+
+    let mut map = TileMap::new(6, 6, TileType::default_rules());
+    map.load_partial(cells).unwrap();
+
+    let walkable = HashSet::from([TileType::Grass, TileType::Sand]);
+    assert_eq!(map.walkable_components(&walkable).len(), 2);
+    assert_eq!(map.enforce_connectivity(&walkable), 1);
+    assert!(matches!(
+      map.get(&isolated),
+      Some(Cell::Collapsed(TileType::Trees))
+    ));
+    assert_complete(&map);
+  }
+
+  #[test]
+  fn cells_with_type_matches_a_scan_of_the_tiles() {
+    let mut map = TileMap::new(16, 16, TileType::default_rules());
+    map.generate_with_seed(3).unwrap();
+
+    for tile_type in TileType::all_types() {
+      let mut expected: Vec<Position> = map
+        .tiles
+        .iter()
+        .filter(|(_, cell)| matches!(cell, Cell::Collapsed(t) if *t == tile_type))
+        .map(|(position, _)| position.clone())
+        .collect();
+      expected.sort_by_key(|position| (position.z, position.y, position.x));
+
+      assert_eq!(map.cells_with_type(&tile_type), expected);
+    }
+  }
+
+  #[test]
+  fn last_streamed_type_is_the_final_type() {
+    let maps = [
+      (TileType::default_rules(), false),
+      (three_colour_rules(), true),
+    ];
+
+    for (rules, wrap) in maps {
+      for seed in 0..5 {
+        let mut map = TileMap::new(9, 9, rules.clone());
+        map.wrap = wrap;
+        map.reseed(seed);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        map.generate_streaming(sender).unwrap();
+        // Collecting keeps the last type sent for each cell.
+        let streamed: HashMap<Position, TileType> = receiver.try_iter().collect();
+
+        assert_eq!(streamed, collapsed(&map));
+      }
+    }
+  }
+
+  /**
+   * Grass and Water that may only be next to each other,
+   * which can't work on a wrapping map with an odd size.
+   */
+  fn impossible_rules() -> TileRules {
+    let mut rules = TileType::default_rules();
+    rules.cleanup.clear();
+
+    for tile_type in TileType::all_types() {
+      let (allowed, weight) = match tile_type {
+        TileType::Grass => (TileSet::from_iter([TileType::Water]), 1.0),
+        TileType::Water => (TileSet::from_iter([TileType::Grass]), 1.0),
+        _ => (TileSet::empty(), 0.0),
+      };
+      rules
+        .adjacency
+        .insert(tile_type.clone(), AdjacencyRule::uniform(allowed));
+      rules.weights.insert(tile_type, weight);
+    }
+
+    rules
+  }
+
+  #[test]
+  fn replay_redoes_softened_cells() {
+    for policy in [
+      ContradictionPolicy::BestEffort,
+      ContradictionPolicy::Soften { after: 2 },
+    ] {
+      for seed in 0..5 {
+        let mut map = TileMap::new(5, 5, impossible_rules());
+        map.wrap = true;
+        map.record = true;
+        map.contradiction_policy = policy;
+        map.generate_with_seed(seed).unwrap();
+
+        let mut replayed = TileMap::new(5, 5, impossible_rules());
+        replayed.wrap = true;
+        replayed.replay(map.log()).unwrap();
+        assert_eq!(collapsed(&replayed), collapsed(&map));
+      }
+    }
+  }
+
+  #[test]
+  fn adjacent_chunk_keeps_the_settings() {
+    let mut map = TileMap::new(8, 8, TileType::default_rules());
+    map.heuristic = CollapseHeuristic::FewestOptions;
+    map.growth_bias = true;
+    map.deterministic_ties = true;
+    map.propagation_order = PropagationOrder::DepthFirst;
+    map.contradiction_policy = ContradictionPolicy::Soften { after: 3 };
+    map.generate_with_seed(1).unwrap();
+
+    let chunk = map.generate_adjacent(&Direction::East);
+    assert_eq!(chunk.heuristic, map.heuristic);
+    assert_eq!(chunk.growth_bias, map.growth_bias);
+    assert_eq!(chunk.deterministic_ties, map.deterministic_ties);
+    assert_eq!(chunk.propagation_order, map.propagation_order);
+    assert_eq!(chunk.contradiction_policy, map.contradiction_policy);
+  }
+
+  #[test]
+  fn resuming_from_rng_state_continues_the_same_way() {
+    for seed in 0..10 {
+      let mut map = TileMap::new(20, 20, TileType::default_rules());
+      map.reseed(seed);
+      for _ in 0..30 {
+        map.step();
+      }
+
+      let cells = collapsed(&map);
+      let state = map.rng_state();
+
+      let mut resumed = TileMap::new(20, 20, TileType::default_rules());
+      resumed.load_partial(cells).unwrap();
+      resumed.set_rng_state(state);
+      let mut in_place = map.clone();
+      in_place.set_rng_state(state);
+
+      map.generate().unwrap();
+      resumed.generate().unwrap();
+      in_place.generate().unwrap();
+      assert_eq!(collapsed(&resumed), collapsed(&map));
+      assert_eq!(collapsed(&in_place), collapsed(&map));
+    }
+  }
+
+  #[test]
+  fn generated_share_follows_the_weights() {
+    // An example that's 80% Grass, with the rest Sand.
+    let grid: Vec<Vec<TileType>> = (0..10)
+      .map(|y| {
+        (0..10)
+          .map(|x| match (x + y * 3) % 5 {
+            0 => TileType::Sand,
+            _ => TileType::Grass,
+          })
+          .collect()
+      })
+      .collect();
+    let mut rules = learn_rules_from_grid(&grid);
+    assert!((rules.weight_of(&TileType::Grass) - 0.8).abs() < 1e-6);
+
+    // Anything may be next to anything, so only the weights decide the share.
+    let both = TileSet::from_iter([TileType::Grass, TileType::Sand]);
+    for tile_type in [TileType::Grass, TileType::Sand] {
+      rules
+        .adjacency
+        .insert(tile_type, AdjacencyRule::every_direction(both));
+    }
+
+    let (mut grass, mut total) = (0, 0);
+    for seed in 0..20 {
+      let mut map = TileMap::new(16, 16, rules.clone());
+      map.generate_with_seed(seed).unwrap();
+      grass += map.count_collapsed(&TileType::Grass);
+      total += map.collapsed_count();
+    }
+
+    let share = grass as f64 / total as f64;
+    assert!(
+      (share - 0.8).abs() < 0.03,
+      "Grass covers {:.3} of the maps",
+      share
+    );
+  }
+
+  #[test]
+  fn sizes_below_one_cell_are_rejected() {
+    for (width, height) in [(0, 5), (5, 0), (-1, 5)] {
+      assert!(matches!(
+        TileMap::try_new(width, height, TileType::default_rules()),
+        Err(WfcError::InvalidSize { .. })
+      ));
+    }
+  }
+
+  #[test]
+  fn invalid_rules_are_rejected() {
+    let mut rules = TileType::default_rules();
+    rules.weights.remove(&TileType::Water);
+
+    match TileMap::try_new(5, 5, rules) {
+      Err(WfcError::InvalidRules(errors)) => {
+        assert_eq!(errors, vec![RuleError::MissingWeight(TileType::Water)])
+      }
+      _ => panic!("a map was made with rules missing a weight"),
+    }
+  }
+
+  #[test]
+  fn updates_of_waiting_cells_are_counted_as_skipped() {
+    let mut map = TileMap::new(5, 5, three_colour_rules());
+    let first = Position { x: 2, y: 2, z: 0 };
+    let second = Position { x: 3, y: 2, z: 0 };
+    // Blank cells start out allowing every type, the first pass narrows them down to the rules' types.
+    map.propagate_from(map.region_positions()).unwrap();
+    let before = map.stats().clone();
+
+    // Nothing changes now, so only the seeds and their neighbours are updated.
+    map.propagate_from(vec![first, second]).unwrap();
+
+    // The second seed and its neighbour the first seed were both already waiting.
+    assert_eq!(map.stats().propagations - before.propagations, 8);
+    assert_eq!(map.stats().skipped_updates - before.skipped_updates, 2);
+  }
+
+  /**
+   * Stone for the ground, Grass on top of it and Water for the air above. Each type is only next to
+   * itself within a layer, and Grass is the only thing between Stone and Water.
+   */
+  fn layered_rules() -> TileRules {
+    let mut rules = TileType::default_rules();
+    rules.cleanup.clear();
+
+    for tile_type in TileType::all_types() {
+      let (up, down, weight) = match tile_type {
+        TileType::Stone => (
+          vec![TileType::Stone, TileType::Grass],
+          vec![TileType::Stone],
+          1.0,
+        ),
+        TileType::Grass => (vec![TileType::Water], vec![TileType::Stone], 1.0),
+        TileType::Water => (
+          vec![TileType::Water],
+          vec![TileType::Grass, TileType::Water],
+          1.0,
+        ),
+        _ => (Vec::new(), Vec::new(), 0.0),
+      };
+
+      let mut rule = AdjacencyRule::uniform(TileSet::from_iter([tile_type.clone()]));
+      rule.up = TileSet::from_iter(up);
+      rule.down = TileSet::from_iter(down);
+      rules.adjacency.insert(tile_type.clone(), rule);
+      rules.weights.insert(tile_type, weight);
     }
 
-    for cell in cells_to_update {
-      self.tiles.insert(cell, Cell::Collapsed(TileType::Water));
+    rules
+  }
+
+  #[test]
+  fn layers_follow_the_vertical_rules() {
+    let rules = layered_rules();
+
+    for seed in 0..5 {
+      let mut map = TileMap::new(6, 6, rules.clone());
+      map.with_depth(4).unwrap();
+      map.generate_with_seed(seed).unwrap();
+      assert_complete(&map);
+
+      let tiles = collapsed(&map);
+      for (position, below) in tiles.iter().filter(|(position, _)| position.z < 3) {
+        let above = &tiles[&Position {
+          z: position.z + 1,
+          ..position.clone()
+        }];
+        assert!(
+          rules.allows(below, &Direction::Up, above),
+          "{:?} on top of {:?}",
+          above,
+          below
+        );
+      }
     }
+
+    // With the ground and the air pinned, only Grass fits in between.
+    let mut map = TileMap::new(6, 6, rules);
+    map.with_depth(3).unwrap();
+    map.set_layer_constraint(0, TileType::Stone);
+    map.set_layer_constraint(2, TileType::Water);
+    map.generate_with_seed(0).unwrap();
+    assert_complete(&map);
+    assert_eq!(map.cells_with_type(&TileType::Grass).len(), 36);
+    assert!(map
+      .cells_with_type(&TileType::Grass)
+      .iter()
+      .all(|position| position.z == 1));
+  }
+
+  #[test]
+  fn scanline_order_goes_layer_by_layer() {
+    let mut map = TileMap::new(4, 4, TileType::default_rules());
+    map.with_depth(2).unwrap();
+    map.heuristic = CollapseHeuristic::ScanlineOrder;
+    map.record = true;
+    map.generate_with_seed(3).unwrap();
+
+    let picked: Vec<(i32, i32, i32)> = map
+      .log()
+      .iter()
+      .filter_map(|event| match event {
+        CollapseEvent::Collapse { position, .. } => Some((position.z, position.y, position.x)),
+        _ => None,
+      })
+      .collect();
+    assert!(picked.iter().any(|(z, _, _)| *z == 1));
+    assert!(picked.windows(2).all(|pair| pair[0] < pair[1]));
   }
 }
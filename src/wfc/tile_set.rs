@@ -0,0 +1,133 @@
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+
+use super::tile_type::TileType;
+
+/**
+ * A set of tile types stored as a bitmask, using `TileType::index` as the bit.
+ * Iterating always yields the types in `TileType::all_types` order.
+ * Serialized as a list of types. When reading a list, `"*"` stands for every type,
+ * so the other entries in a list with it don't change anything.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<SetEntry>", into = "Vec<TileType>")]
+pub struct TileSet(u64);
+
+const WILDCARD: &str = "*";
+
+/**
+ * An entry of a serialized set, a type or the wildcard.
+ */
+enum SetEntry {
+  Type(TileType),
+  Wildcard,
+}
+
+impl<'de> Deserialize<'de> for SetEntry {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    if name == WILDCARD {
+      return Ok(SetEntry::Wildcard);
+    }
+
+    TileType::deserialize(name.into_deserializer()).map(SetEntry::Type)
+  }
+}
+
+impl TileSet {
+  pub fn empty() -> TileSet {
+    TileSet(0)
+  }
+
+  pub fn all() -> TileSet {
+    TileType::all_types().into_iter().collect()
+  }
+
+  fn bit(tile_type: &TileType) -> u64 {
+    1 << tile_type.index()
+  }
+
+  pub fn contains(&self, tile_type: &TileType) -> bool {
+    self.0 & TileSet::bit(tile_type) != 0
+  }
+
+  pub fn insert(&mut self, tile_type: TileType) {
+    self.0 |= TileSet::bit(&tile_type);
+  }
+
+  /**
+   * Removes the type from the set, returns whether it was in the set.
+   */
+  pub fn remove(&mut self, tile_type: &TileType) -> bool {
+    let contained = self.contains(tile_type);
+    self.0 &= !TileSet::bit(tile_type);
+    contained
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.count_ones() as usize
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0 == 0
+  }
+
+  pub fn iter(&self) -> TileSetIter {
+    TileSetIter(self.0)
+  }
+}
+
+impl FromIterator<TileType> for TileSet {
+  fn from_iter<I: IntoIterator<Item = TileType>>(iter: I) -> Self {
+    let mut set = TileSet::empty();
+
+    for tile_type in iter {
+      set.insert(tile_type);
+    }
+
+    set
+  }
+}
+
+pub struct TileSetIter(u64);
+
+impl Iterator for TileSetIter {
+  type Item = TileType;
+
+  fn next(&mut self) -> Option<TileType> {
+    if self.0 == 0 {
+      return None;
+    }
+
+    let index = self.0.trailing_zeros() as usize;
+    self.0 &= self.0 - 1;
+
+    Some(TileType::from_index(index))
+  }
+}
+
+impl From<Vec<TileType>> for TileSet {
+  fn from(types: Vec<TileType>) -> Self {
+    types.into_iter().collect()
+  }
+}
+
+impl From<Vec<SetEntry>> for TileSet {
+  fn from(entries: Vec<SetEntry>) -> Self {
+    let mut set = TileSet::empty();
+
+    for entry in entries {
+      match entry {
+        SetEntry::Type(tile_type) => set.insert(tile_type),
+        SetEntry::Wildcard => return TileSet::all(),
+      }
+    }
+
+    set
+  }
+}
+
+impl From<TileSet> for Vec<TileType> {
+  fn from(set: TileSet) -> Self {
+    set.iter().collect()
+  }
+}
@@ -1,13 +1,648 @@
-use bevy::utils::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet};
+
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{
+  error::{RuleError, RulesJsonError},
+  tile_map::{Direction, ALL_DIRECTIONS, DIRECTIONS},
+  tile_set::TileSet,
+};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TileRules {
-  pub adjacency: HashMap<TileType, HashSet<TileType>>,
-  pub weights: HashMap<TileType, i32>,
+  pub adjacency: HashMap<TileType, AdjacencyRule>,
+  /**
+   * How likely each type is to be picked compared to the others, fractions are fine.
+   */
+  pub weights: HashMap<TileType, f32>,
+  #[serde(default)]
+  pub cleanup: Vec<CleanupRule>,
+  /**
+   * Labels for groups of tiles, so `tag_adjacency` can allow whole groups next to each other.
+   */
+  #[serde(default)]
+  pub tags: HashMap<TileType, HashSet<String>>,
+  #[serde(default)]
+  pub tag_adjacency: Vec<TagRule>,
+  /**
+   * Types folded into an equivalent type by `merge_equivalent`, they're never picked.
+   */
+  #[serde(default)]
+  pub merged: HashMap<TileType, TileType>,
+  #[serde(default)]
+  pub texture_rules: Vec<TextureRule>,
+  /**
+   * Pairs of types that may not touch only at a corner, checked against collapsed diagonal neighbours
+   * on square maps. Unlike the diagonal sets this works without `diagonal`, as nothing is propagated.
+   */
+  #[serde(default)]
+  pub forbidden_corners: Vec<(TileType, TileType)>,
+  /**
+   * Scales the weight of a type when it's picked next to itself, depending on the side, see `DirectionalWeight`.
+   */
+  #[serde(default)]
+  pub directional_weights: HashMap<TileType, DirectionalWeight>,
+  /**
+   * Anything a game wants to know about a type, like whether it can be walked on.
+   * Generation never looks at it, see `metadata`.
+   */
+  #[serde(default)]
+  pub metadata: HashMap<TileType, HashMap<String, String>>,
+  /**
+   * The size of a tile in pixels, tiles don't have to be square.
+   */
+  #[serde(rename = "tileWidth", default = "default_tile_size")]
+  pub tile_width: f32,
+  #[serde(rename = "tileHeight", default = "default_tile_size")]
+  pub tile_height: f32,
+  /**
+   * How many tiles fit side by side in the tileset image, for games that draw the tiles from one sheet.
+   */
+  #[serde(rename = "tilesetColumns", default = "default_tileset_columns")]
+  pub tileset_columns: u32,
+}
+
+/**
+ * The tile width and height used when the rules don't give one.
+ */
+pub const DEFAULT_TILE_SIZE: f32 = 32.0;
+/**
+ * The columns of a 160 pixel wide tileset of 32 pixel tiles.
+ */
+pub const DEFAULT_TILESET_COLUMNS: u32 = 5;
+
+fn default_tile_size() -> f32 {
+  DEFAULT_TILE_SIZE
+}
+
+fn default_tileset_columns() -> u32 {
+  DEFAULT_TILESET_COLUMNS
+}
+
+/**
+ * The tiles allowed next to a tile, per direction.
+ * A tile's set for a direction lists the tiles allowed in the cell in that direction from it.
+ * Two tiles can only be neighbours if both of them allow the other.
+ * The diagonal sets are only used when a map has `diagonal` enabled or a hex topology,
+ * when they are left out of a saved rule they allow anything. The same goes for the sets up and down,
+ * which are only used by maps with a depth.
+ * In a saved rule `"*"` allows every type in that direction, the other types still have to allow this one back.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdjacencyRule {
+  pub north: TileSet,
+  pub east: TileSet,
+  pub south: TileSet,
+  pub west: TileSet,
+  #[serde(default = "TileSet::all")]
+  pub north_east: TileSet,
+  #[serde(default = "TileSet::all")]
+  pub north_west: TileSet,
+  #[serde(default = "TileSet::all")]
+  pub south_east: TileSet,
+  #[serde(default = "TileSet::all")]
+  pub south_west: TileSet,
+  #[serde(default = "TileSet::all")]
+  pub up: TileSet,
+  #[serde(default = "TileSet::all")]
+  pub down: TileSet,
+}
+
+impl AdjacencyRule {
+  /**
+   * A rule with the given sets for the four sides that allows anything diagonally, up and down.
+   */
+  pub fn new(north: TileSet, east: TileSet, south: TileSet, west: TileSet) -> AdjacencyRule {
+    AdjacencyRule {
+      north,
+      east,
+      south,
+      west,
+      north_east: TileSet::all(),
+      north_west: TileSet::all(),
+      south_east: TileSet::all(),
+      south_west: TileSet::all(),
+      up: TileSet::all(),
+      down: TileSet::all(),
+    }
+  }
+
+  /**
+   * A rule that allows the same tiles on every side, and anything diagonally, up and down.
+   */
+  pub fn uniform(allowed: TileSet) -> AdjacencyRule {
+    AdjacencyRule::new(allowed, allowed, allowed, allowed)
+  }
+
+  /**
+   * A rule that allows the same tiles in every direction, diagonals, up and down included.
+   * Hex maps need this, as most of their neighbours are diagonal.
+   */
+  pub fn every_direction(allowed: TileSet) -> AdjacencyRule {
+    AdjacencyRule {
+      north_east: allowed,
+      north_west: allowed,
+      south_east: allowed,
+      south_west: allowed,
+      up: allowed,
+      down: allowed,
+      ..AdjacencyRule::uniform(allowed)
+    }
+  }
+
+  pub fn get(&self, direction: &Direction) -> &TileSet {
+    match direction {
+      Direction::North => &self.north,
+      Direction::East => &self.east,
+      Direction::South => &self.south,
+      Direction::West => &self.west,
+      Direction::NorthEast => &self.north_east,
+      Direction::NorthWest => &self.north_west,
+      Direction::SouthEast => &self.south_east,
+      Direction::SouthWest => &self.south_west,
+      Direction::Up => &self.up,
+      Direction::Down => &self.down,
+    }
+  }
+
+  pub fn get_mut(&mut self, direction: &Direction) -> &mut TileSet {
+    match direction {
+      Direction::North => &mut self.north,
+      Direction::East => &mut self.east,
+      Direction::South => &mut self.south,
+      Direction::West => &mut self.west,
+      Direction::NorthEast => &mut self.north_east,
+      Direction::NorthWest => &mut self.north_west,
+      Direction::SouthEast => &mut self.south_east,
+      Direction::SouthWest => &mut self.south_west,
+      Direction::Up => &mut self.up,
+      Direction::Down => &mut self.down,
+    }
+  }
+}
+
+/**
+ * Allows tiles tagged `tag` and tiles tagged with any of `neighbours` next to each other on every side.
+ * Applied on top of the per-tile adjacency by `TileRules::expand_tags`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagRule {
+  pub tag: String,
+  pub neighbours: Vec<String>,
+}
+
+/**
+ * Draws `tile` with `texture` when its collapsed neighbours match, sides that are left out match anything.
+ * Lets a type use a texture that faces the right way, like the edge of a cliff.
+ * With `rotate` the sides also match turned around, and the texture is drawn turned the same way,
+ * so one texture covers all four directions.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextureRule {
+  pub tile: TileType,
+  #[serde(default)]
+  pub north: Option<TileType>,
+  #[serde(default)]
+  pub east: Option<TileType>,
+  #[serde(default)]
+  pub south: Option<TileType>,
+  #[serde(default)]
+  pub west: Option<TileType>,
+  pub texture: String,
+  #[serde(default)]
+  pub rotate: bool,
+}
+
+/**
+ * How much more likely a type is to be picked for each side that has a collapsed cell of the same type,
+ * so the type grows one way more than the others. `north: 4.0` makes it four times as likely
+ * right south of itself, like a river flowing south. Sides that are left out are 1.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirectionalWeight {
+  #[serde(default = "unscaled")]
+  pub north: f32,
+  #[serde(default = "unscaled")]
+  pub east: f32,
+  #[serde(default = "unscaled")]
+  pub south: f32,
+  #[serde(default = "unscaled")]
+  pub west: f32,
+}
+
+fn unscaled() -> f32 {
+  1.0
+}
+
+impl DirectionalWeight {
+  /**
+   * The factor for `tile_type` next to the given neighbours, one factor for every side it's repeated on.
+   */
+  pub fn factor(&self, tile_type: &TileType, neighbours: &NeighbourTypes) -> f32 {
+    [
+      (&neighbours.north, self.north),
+      (&neighbours.east, self.east),
+      (&neighbours.south, self.south),
+      (&neighbours.west, self.west),
+    ]
+    .iter()
+    .filter(|(neighbour, _)| neighbour.as_ref() == Some(tile_type))
+    .map(|(_, factor)| factor.max(0.0))
+    .product()
+  }
+}
+
+/**
+ * The types of the cells on each side of a cell, `None` when that cell isn't collapsed or is outside the map.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NeighbourTypes {
+  pub north: Option<TileType>,
+  pub east: Option<TileType>,
+  pub south: Option<TileType>,
+  pub west: Option<TileType>,
+}
+
+/**
+ * A post-processing step that replaces `tile` with `replace_with`
+ * when none of its surrounding tiles is `requires_neighbour`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CleanupRule {
+  pub tile: TileType,
+  pub requires_neighbour: TileType,
+  pub replace_with: TileType,
+}
+
+impl TileRules {
+  /**
+   * Saves the rules so they can be edited and loaded again with `from_json`.
+   */
+  pub fn to_json(&self) -> serde_json::Value {
+    serde_json::to_value(self).unwrap()
+  }
+
+  /**
+   * Loads rules saved with `to_json`, expands their tag rules and checks them with `validate`.
+   * Cleanup rules, tags and diagonal sets may be left out. A type name that isn't a `TileType`
+   * is rejected by serde, so it ends up as a `RulesJsonError::Malformed`.
+   */
+  pub fn from_json(json: &serde_json::Value) -> Result<TileRules, RulesJsonError> {
+    let mut rules = TileRules::deserialize(json)?;
+    rules.expand_tags();
+    rules.validate().map_err(RulesJsonError::Invalid)?;
+    Ok(rules)
+  }
+
+  /**
+   * The types that have the tag.
+   */
+  pub fn tagged(&self, tag: &str) -> TileSet {
+    TileType::all_types()
+      .into_iter()
+      .filter(|tile_type| {
+        self
+          .tags
+          .get(tile_type)
+          .is_some_and(|tags| tags.contains(tag))
+      })
+      .collect()
+  }
+
+  /**
+   * Adds the pairs allowed by `tag_adjacency` to the adjacency of every tile involved,
+   * both ways round so the pairs pass `allows`.
+   * Types without an adjacency rule get one allowing nothing else.
+   */
+  pub fn expand_tags(&mut self) {
+    let sides = [
+      Direction::North,
+      Direction::East,
+      Direction::South,
+      Direction::West,
+      Direction::Up,
+      Direction::Down,
+    ];
+
+    for rule in self.tag_adjacency.clone() {
+      let tiles = self.tagged(&rule.tag);
+      let neighbours: TileSet = rule
+        .neighbours
+        .iter()
+        .flat_map(|tag| self.tagged(tag).iter())
+        .collect();
+
+      for (a, b) in [(tiles, neighbours), (neighbours, tiles)] {
+        for tile_type in a.iter() {
+          let adjacency = self
+            .adjacency
+            .entry(tile_type)
+            .or_insert_with(|| AdjacencyRule::uniform(TileSet::empty()));
+
+          for side in sides.iter() {
+            for neighbour in b.iter() {
+              adjacency.get_mut(side).insert(neighbour);
+            }
+          }
+        }
+      }
+    }
+  }
+
+  /**
+   * Finds types that are interchangeable, with the same weight and the same neighbours in every direction,
+   * and folds each group into its first type in `all_types` order.
+   * The weight of the kept type grows so the group is picked as often as before,
+   * the folded types are taken out of every rule and are never picked.
+   * Maps copy their rules when they're created, so this has to run before `TileMap::new`.
+   * Returns the folded types with the type they were folded into.
+   */
+  pub fn merge_equivalent(&mut self) -> HashMap<TileType, TileType> {
+    let mut merges = HashMap::new();
+    let types: Vec<TileType> = TileType::all_types()
+      .into_iter()
+      .filter(|tile_type| !self.merged.contains_key(tile_type))
+      .collect();
+
+    for (i, a) in types.iter().enumerate() {
+      if merges.contains_key(a) {
+        continue;
+      }
+
+      for b in types[i + 1..].iter() {
+        if !merges.contains_key(b) && self.interchangeable(a, b) {
+          merges.insert(b.clone(), a.clone());
+        }
+      }
+    }
+
+    for (merged, kept) in merges.iter() {
+      let weight = self.weight_of(kept) + self.weight_of(merged);
+      self.weights.insert(kept.clone(), weight);
+
+      for cleanup in self.cleanup.iter_mut() {
+        for tile_type in [
+          &mut cleanup.tile,
+          &mut cleanup.requires_neighbour,
+          &mut cleanup.replace_with,
+        ] {
+          if tile_type == merged {
+            *tile_type = kept.clone();
+          }
+        }
+      }
+    }
+
+    for adjacency in self.adjacency.values_mut() {
+      for direction in ALL_DIRECTIONS.iter() {
+        for merged in merges.keys() {
+          adjacency.get_mut(direction).remove(merged);
+        }
+      }
+    }
+
+    self.merged.extend(merges.clone());
+    merges
+  }
+
+  /**
+   * Whether `a` and `b` could be swapped anywhere without breaking a rule.
+   */
+  fn interchangeable(&self, a: &TileType, b: &TileType) -> bool {
+    if self.weights.get(a) != self.weights.get(b) {
+      return false;
+    }
+
+    // Directional weights depend on a type being next to itself, which merging would change.
+    if self.directional_weights.contains_key(a) || self.directional_weights.contains_key(b) {
+      return false;
+    }
+
+    // The game can tell the two apart.
+    if self.metadata.get(a) != self.metadata.get(b) || self.tags.get(a) != self.tags.get(b) {
+      return false;
+    }
+
+    let same_corners = TileType::all_types()
+      .iter()
+      .all(|other| self.corner_allowed(a, other) == self.corner_allowed(b, other));
+    if !same_corners || self.corner_allowed(a, a) != self.corner_allowed(b, b) {
+      return false;
+    }
+
+    let (rule_a, rule_b) = match (self.adjacency.get(a), self.adjacency.get(b)) {
+      (Some(rule_a), Some(rule_b)) => (rule_a, rule_b),
+      _ => return false,
+    };
+
+    ALL_DIRECTIONS.iter().all(|direction| {
+      let (set_a, set_b) = (rule_a.get(direction), rule_b.get(direction));
+      // Next to each other and to themselves, the two have to be allowed all or nothing.
+      let same_with_each_other = set_a.contains(a) == set_a.contains(b);
+
+      set_a == set_b
+        && same_with_each_other
+        && self.adjacency.iter().all(|(other, rule)| {
+          other == a
+            || other == b
+            || rule.get(direction).contains(a) == rule.get(direction).contains(b)
+        })
+    })
+  }
+
+  /**
+   * Whether `a` and `b` can be diagonal neighbours, in either order.
+   */
+  pub fn corner_allowed(&self, a: &TileType, b: &TileType) -> bool {
+    !self
+      .forbidden_corners
+      .iter()
+      .any(|(first, second)| (first == a && second == b) || (first == b && second == a))
+  }
+
+  /**
+   * The texture of the first texture rule for the type that matches its neighbours,
+   * with the number of quarter turns clockwise it has to be drawn with.
+   * A rule that rotates is tried unturned first.
+   */
+  pub fn texture_for(
+    &self,
+    tile_type: &TileType,
+    neighbours: &NeighbourTypes,
+  ) -> Option<(&str, u8)> {
+    let matches =
+      |side: &Option<TileType>, neighbour: &Option<TileType>| side.is_none() || side == neighbour;
+    // Clockwise from north, turning a rule moves each of its sides one step along.
+    let around = [
+      &neighbours.north,
+      &neighbours.east,
+      &neighbours.south,
+      &neighbours.west,
+    ];
+
+    self
+      .texture_rules
+      .iter()
+      .filter(|rule| rule.tile == *tile_type)
+      .find_map(|rule| {
+        let sides = [&rule.north, &rule.east, &rule.south, &rule.west];
+        let turns = if rule.rotate { 4 } else { 1 };
+
+        (0..turns)
+          .find(|turn| (0..4).all(|side| matches(sides[side], around[(side + *turn as usize) % 4])))
+          .map(|turn| (rule.texture.as_str(), turn))
+      })
+  }
+
+  /**
+   * The types that can never have neighbours on all four sides, because on some side nothing that can
+   * appear allows them, so they only show up along the edges of a map if at all.
+   * Repeats until no more types drop out, as a type only allowed next to those doesn't appear either.
+   * Types without a weight or adjacency rule never appear, merged types are left out.
+   */
+  pub fn find_unreachable(&self) -> Vec<TileType> {
+    let mut placeable: TileSet = TileType::all_types()
+      .into_iter()
+      .filter(|tile_type| {
+        self.adjacency.contains_key(tile_type)
+          && self.weight_of(tile_type) > 0.0
+          && !self.merged.contains_key(tile_type)
+      })
+      .collect();
+
+    loop {
+      let unsupported: Vec<TileType> = placeable
+        .iter()
+        .filter(|tile_type| {
+          DIRECTIONS.iter().any(|direction| {
+            !placeable
+              .iter()
+              .any(|other| self.allows(tile_type, direction, &other))
+          })
+        })
+        .collect();
+      if unsupported.is_empty() {
+        break;
+      }
+
+      for tile_type in unsupported.iter() {
+        placeable.remove(tile_type);
+      }
+    }
+
+    TileType::all_types()
+      .into_iter()
+      .filter(|tile_type| !placeable.contains(tile_type) && !self.merged.contains_key(tile_type))
+      .collect()
+  }
+
+  /**
+   * The adjacency rules as a Graphviz graph, with an edge from `a` to `b` labelled with the direction
+   * for every type `a`'s rule allows there. Edges that `b`'s rule doesn't allow back are dashed.
+   */
+  pub fn to_dot(&self) -> String {
+    let mut output = String::from("digraph rules {\n");
+
+    for tile_type in TileType::all_types() {
+      output.push_str(&format!("  {:?};\n", tile_type));
+    }
+
+    for a in TileType::all_types() {
+      let rule = match self.adjacency.get(&a) {
+        Some(rule) => rule,
+        None => continue,
+      };
+
+      for (direction, label) in DIRECTIONS.iter().zip(["N", "E", "S", "W"]) {
+        for b in rule.get(direction).iter() {
+          let symmetric = match self.adjacency.get(&b) {
+            Some(_) => self.allows_towards(&b, &direction.opposite(), &a),
+            None => false,
+          };
+          let style = if symmetric { "" } else { ", style=dashed" };
+          output.push_str(&format!(
+            "  {:?} -> {:?} [label=\"{}\"{}];\n",
+            a, b, label, style
+          ));
+        }
+      }
+    }
+
+    output.push_str("}\n");
+    output
+  }
+
+  /**
+   * The metadata of a type, `None` for types without any.
+   */
+  pub fn metadata(&self, tile_type: &TileType) -> Option<&HashMap<String, String>> {
+    self.metadata.get(tile_type)
+  }
+
+  /**
+   * Checks that every tile type has an adjacency rule and a usable weight.
+   * Returns every problem that was found.
+   */
+  pub fn validate(&self) -> Result<(), Vec<RuleError>> {
+    let mut errors = Vec::new();
+
+    for tile_type in TileType::all_types() {
+      if !self.adjacency.contains_key(&tile_type) {
+        errors.push(RuleError::MissingAdjacency(tile_type.clone()));
+      }
+
+      match self.weights.get(&tile_type) {
+        // Merged types are never picked, their weight doesn't matter.
+        _ if self.merged.contains_key(&tile_type) => {}
+        None => errors.push(RuleError::MissingWeight(tile_type)),
+        Some(weight) if *weight < 0.0 => errors.push(RuleError::NegativeWeight(tile_type, *weight)),
+        Some(_) => {}
+      }
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+
+  /**
+   * Whether `b` can be in the cell in the given direction from `a`.
+   */
+  pub fn allows(&self, a: &TileType, direction: &Direction, b: &TileType) -> bool {
+    self.allows_towards(a, direction, b) && self.allows_towards(b, &direction.opposite(), a)
+  }
+
+  /**
+   * Whether `a`'s rule lists `b` for the given direction, without checking `b`'s rule.
+   * A type without an adjacency rule allows nothing.
+   */
+  pub fn allows_towards(&self, a: &TileType, direction: &Direction, b: &TileType) -> bool {
+    self
+      .adjacency
+      .get(a)
+      .is_some_and(|rule| rule.get(direction).contains(b))
+  }
+
+  /**
+   * The weight used when picking a random type.
+   * Types without a weight, with a negative one or that were merged get 0, they are never picked.
+   */
+  pub fn weight_of(&self, tile_type: &TileType) -> f32 {
+    if self.merged.contains_key(tile_type) {
+      return 0.0;
+    }
+
+    match self.weights.get(tile_type) {
+      Some(weight) => weight.max(0.0),
+      None => 0.0,
+    }
+  }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum TileType {
   Grass,
   Water,
@@ -18,80 +653,311 @@ pub enum TileType {
 
 impl TileType {
   pub fn all_types() -> Vec<TileType> {
-    return vec![
+    vec![
       TileType::Grass,
       TileType::Water,
       TileType::Sand,
       TileType::Trees,
       TileType::Stone,
-    ];
+    ]
   }
 
-  pub fn random_from_set(set: &HashSet<TileType>, rules: &TileRules) -> TileType {
-    let mut types = Vec::new();
+  /**
+   * The bit of this type in a `TileSet`, its position in `all_types`.
+   */
+  pub fn index(&self) -> usize {
+    self.clone() as usize
+  }
 
-    set.iter().for_each(|t| {
-      for _ in 0..(rules.weights.get(t).expect("invalid type") + 1) {
-        types.push(t.clone());
+  pub fn from_index(index: usize) -> TileType {
+    match index {
+      0 => TileType::Grass,
+      1 => TileType::Water,
+      2 => TileType::Sand,
+      3 => TileType::Trees,
+      4 => TileType::Stone,
+      _ => panic!("invalid tile index {}", index),
+    }
+  }
+
+  /**
+   * Picks a weighted random type from the set.
+   * Sets are visited in `all_types` order so the result only depends on the rng.
+   * Returns `None` when no type in the set has a weight above 0.
+   */
+  pub fn random_from_set(set: &TileSet, rules: &TileRules, rng: &mut impl Rng) -> Option<TileType> {
+    let total: f32 = set.iter().map(|t| rules.weight_of(&t)).sum();
+    if total <= 0.0 {
+      return None;
+    }
+
+    let mut pick = rng.gen_range(0.0..total);
+    let mut last = None;
+
+    for tile_type in set.iter().filter(|t| rules.weight_of(t) > 0.0) {
+      let weight = rules.weight_of(&tile_type);
+      if pick < weight {
+        return Some(tile_type);
       }
-    });
+      pick -= weight;
+      last = Some(tile_type);
+    }
+
+    // Rounding can leave the pick just above the last weight.
+    last
+  }
+
+  /**
+   * The character the type is drawn with as text, see `TileMap::to_ascii`.
+   */
+  pub fn symbol(&self) -> char {
+    match self {
+      TileType::Grass => '.',
+      TileType::Water => '~',
+      TileType::Sand => ':',
+      TileType::Trees => 'T',
+      TileType::Stone => '^',
+    }
+  }
+
+  /**
+   * Every type with its `symbol`, a legend for `TileMap::to_ascii` to start from.
+   */
+  pub fn legend() -> HashMap<TileType, char> {
+    TileType::all_types()
+      .into_iter()
+      .map(|tile_type| (tile_type.clone(), tile_type.symbol()))
+      .collect()
+  }
 
-    let index = rand::thread_rng().gen_range(0..types.len());
-    types.get(index).unwrap().clone()
+  /**
+   * The textures a type can be drawn with, each with the weight it's picked with.
+   */
+  pub fn texture_variants(&self) -> Vec<(&'static str, u32)> {
+    match self {
+      TileType::Grass => vec![("tiles/Grass.png", 1)],
+      TileType::Water => vec![("tiles/Water.png", 1)],
+      TileType::Sand => vec![("tiles/Sand.png", 1)],
+      TileType::Trees => vec![("tiles/Trees01.png", 1), ("tiles/Trees02.png", 1)],
+      TileType::Stone => vec![("tiles/Rock.png", 1)],
+    }
   }
 
-  pub fn get_texture(tile_type: &TileType) -> String {
-    match tile_type {
-      TileType::Grass => "tiles/Grass.png".to_string(),
-      TileType::Water => "tiles/Water.png".to_string(),
-      TileType::Sand => "tiles/Sand.png".to_string(),
-      TileType::Trees => [
-        "tiles/Trees01.png".to_string(),
-        "tiles/Trees02.png".to_string(),
-      ]
-      .get(rand::thread_rng().gen_range(0..2))
-      .unwrap()
-      .clone(),
-      TileType::Stone => "tiles/Rock.png".to_string(),
+  /**
+   * Picks a weighted random texture from the type's variants.
+   * Using a seeded rng picks the same textures for the same map every time.
+   * Returns `None` if the type has no variant with a weight above 0.
+   */
+  pub fn get_texture(tile_type: &TileType, rng: &mut impl Rng) -> Option<String> {
+    let variants = tile_type.texture_variants();
+    let total: u32 = variants.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+      return None;
+    }
+
+    let mut pick = rng.gen_range(0..total);
+
+    for (path, weight) in variants.iter() {
+      if pick < *weight {
+        return Some(path.to_string());
+      }
+      pick -= weight;
     }
+
+    unreachable!("the pick is always below the total weight")
   }
 
   pub fn default_rules() -> TileRules {
     let mut adjacency_rules = HashMap::new();
     adjacency_rules.insert(
       TileType::Grass,
-      HashSet::from_iter(vec![
+      AdjacencyRule::uniform(TileSet::from_iter(vec![
         TileType::Grass,
         TileType::Trees,
         TileType::Sand,
         TileType::Stone,
-      ]),
+      ])),
     );
     adjacency_rules.insert(
       TileType::Water,
-      HashSet::from_iter(vec![TileType::Water, TileType::Sand]),
+      AdjacencyRule::uniform(TileSet::from_iter(vec![TileType::Water, TileType::Sand])),
     );
     adjacency_rules.insert(
       TileType::Sand,
-      HashSet::from_iter(vec![TileType::Sand, TileType::Water, TileType::Grass]),
+      AdjacencyRule::uniform(TileSet::from_iter(vec![
+        TileType::Sand,
+        TileType::Water,
+        TileType::Grass,
+      ])),
     );
     adjacency_rules.insert(
       TileType::Trees,
-      HashSet::from_iter(vec![TileType::Trees, TileType::Grass]),
+      AdjacencyRule::uniform(TileSet::from_iter(vec![TileType::Trees, TileType::Grass])),
+    );
+    adjacency_rules.insert(
+      TileType::Stone,
+      AdjacencyRule::uniform(TileSet::from_iter(vec![TileType::Grass])),
     );
-    adjacency_rules.insert(TileType::Stone, HashSet::from_iter(vec![TileType::Grass]));
 
     let mut weights = HashMap::new();
 
-    weights.insert(TileType::Grass, 5);
-    weights.insert(TileType::Water, 1);
-    weights.insert(TileType::Sand, 1);
-    weights.insert(TileType::Trees, 3);
-    weights.insert(TileType::Stone, 3);
+    weights.insert(TileType::Grass, 6.0);
+    weights.insert(TileType::Water, 2.0);
+    weights.insert(TileType::Sand, 2.0);
+    weights.insert(TileType::Trees, 4.0);
+    weights.insert(TileType::Stone, 4.0);
 
-    return TileRules {
+    // Sand that doesn't touch any grass would form small islands in the water.
+    let cleanup = vec![CleanupRule {
+      tile: TileType::Sand,
+      requires_neighbour: TileType::Grass,
+      replace_with: TileType::Water,
+    }];
+
+    TileRules {
       adjacency: adjacency_rules,
       weights,
+      cleanup,
+      tags: HashMap::new(),
+      tag_adjacency: Vec::new(),
+      merged: HashMap::new(),
+      texture_rules: Vec::new(),
+      forbidden_corners: Vec::new(),
+      directional_weights: HashMap::new(),
+      metadata: HashMap::new(),
+      tile_width: DEFAULT_TILE_SIZE,
+      tile_height: DEFAULT_TILE_SIZE,
+      tileset_columns: DEFAULT_TILESET_COLUMNS,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wfc::{
+    cell::Cell,
+    tile_map::{Position, TileMap},
+  };
+
+  #[test]
+  fn asymmetric_rule_only_allows_its_own_direction() {
+    let mut rules = TileType::default_rules();
+    rules.cleanup.clear();
+
+    // Water may be west of Sand, but not on any other side of it.
+    // Water and Grass may touch on every side, so Water isn't pushed against the map's edges.
+    let grass = rules.adjacency.get_mut(&TileType::Grass).unwrap();
+    *grass = AdjacencyRule::uniform(TileSet::all());
+    let water = rules.adjacency.get_mut(&TileType::Water).unwrap();
+    *water = AdjacencyRule::uniform(TileSet::from_iter([TileType::Water, TileType::Grass]));
+    water.east.insert(TileType::Sand);
+    let sand = rules.adjacency.get_mut(&TileType::Sand).unwrap();
+    sand.north.remove(&TileType::Water);
+    sand.east.remove(&TileType::Water);
+    sand.south.remove(&TileType::Water);
+
+    assert!(rules.allows(&TileType::Sand, &Direction::West, &TileType::Water));
+    assert!(rules.allows(&TileType::Water, &Direction::East, &TileType::Sand));
+    assert!(!rules.allows(&TileType::Sand, &Direction::East, &TileType::Water));
+    assert!(!rules.allows(&TileType::Water, &Direction::West, &TileType::Sand));
+
+    let collapsed = |map: &TileMap, x, y| match map.tiles.get(&Position { x, y, z: 0 }) {
+      Some(Cell::Collapsed(tile_type)) => Some(tile_type.clone()),
+      _ => None,
     };
+    let mut water_west_of_sand = 0;
+
+    for seed in 0..10 {
+      let mut map = TileMap::new(16, 16, rules.clone());
+      map.generate_with_seed(seed).unwrap();
+
+      for y in 0..16 {
+        for x in 0..15 {
+          match (collapsed(&map, x, y), collapsed(&map, x + 1, y)) {
+            (Some(TileType::Sand), Some(TileType::Water)) => panic!("Water east of Sand"),
+            (Some(TileType::Water), Some(TileType::Sand)) => water_west_of_sand += 1,
+            _ => {}
+          }
+        }
+      }
+    }
+
+    assert!(water_west_of_sand > 0);
+  }
+
+  #[test]
+  fn default_rules_round_trip_through_json() {
+    let rules = TileType::default_rules();
+    let text = serde_json::to_string_pretty(&rules.to_json()).unwrap();
+    let json = serde_json::from_str(&text).unwrap();
+
+    assert_eq!(TileRules::from_json(&json).unwrap(), rules);
+  }
+
+  #[test]
+  fn default_rules_file_matches_the_default_rules() {
+    let text = include_str!("../../assets/rules/default.rules");
+    let json = serde_json::from_str(text).unwrap();
+
+    assert_eq!(
+      TileRules::from_json(&json).unwrap(),
+      TileType::default_rules()
+    );
+  }
+
+  #[test]
+  fn validate_reports_every_problem() {
+    let mut rules = TileType::default_rules();
+    rules.adjacency.remove(&TileType::Sand);
+    rules.weights.remove(&TileType::Trees);
+    rules.weights.insert(TileType::Stone, -1.0);
+
+    let errors = vec![
+      RuleError::MissingAdjacency(TileType::Sand),
+      RuleError::MissingWeight(TileType::Trees),
+      RuleError::NegativeWeight(TileType::Stone, -1.0),
+    ];
+    assert_eq!(rules.validate(), Err(errors.clone()));
+    assert_eq!(
+      TileRules::from_json(&rules.to_json()),
+      Err(RulesJsonError::Invalid(errors))
+    );
+
+    // Sand has no rule left, so nothing may be next to it.
+    assert!(!rules.allows(&TileType::Grass, &Direction::North, &TileType::Sand));
+  }
+
+  #[test]
+  fn unknown_type_names_are_rejected() {
+    let mut json = TileType::default_rules().to_json();
+    json["weights"]["Lava"] = serde_json::json!(1.0);
+
+    assert!(matches!(
+      TileRules::from_json(&json),
+      Err(RulesJsonError::Malformed(_))
+    ));
+  }
+
+  #[test]
+  fn tile_size_is_read_from_the_rules() {
+    let mut json = TileType::default_rules().to_json();
+    let saved = json.as_object_mut().unwrap();
+    saved.remove("tileWidth");
+    saved.remove("tileHeight");
+    saved.remove("tilesetColumns");
+
+    let rules = TileRules::from_json(&json).unwrap();
+    assert_eq!(rules.tile_width, DEFAULT_TILE_SIZE);
+    assert_eq!(rules.tile_height, DEFAULT_TILE_SIZE);
+    assert_eq!(rules.tileset_columns, DEFAULT_TILESET_COLUMNS);
+
+    json["tileWidth"] = serde_json::json!(16);
+    json["tileHeight"] = serde_json::json!(24);
+    json["tilesetColumns"] = serde_json::json!(8);
+
+    let rules = TileRules::from_json(&json).unwrap();
+    assert_eq!((rules.tile_width, rules.tile_height), (16.0, 24.0));
+    assert_eq!(rules.tileset_columns, 8);
   }
 }
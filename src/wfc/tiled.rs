@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use quick_xml::{
+  events::{BytesStart, Event},
+  Reader,
+};
+
+use super::{error::TiledError, tile_map::Position, tile_type::TileType};
+
+/**
+ * The bits Tiled uses to store how a tile is flipped or rotated, types can't be flipped so they're ignored.
+ */
+const FLIP_FLAGS: u32 = 0xF000_0000;
+
+/**
+ * Reads the tile ids of a layer from a `.tmx` map, indexed as `grid[y][x]`.
+ * The layer has to be saved with the CSV encoding, which is Tiled's default.
+ */
+pub fn layer_from_tmx(tmx: &str, layer: &str) -> Result<Vec<Vec<u32>>, TiledError> {
+  let not_csv = || TiledError::NotCsv(layer.to_string());
+  let mut reader = Reader::from_str(tmx);
+
+  let width = loop {
+    match reader.read_event()? {
+      Event::Start(tag) if is_layer(&tag, layer)? => {
+        break attribute(&tag, "width")?.and_then(|width| width.parse::<usize>().ok());
+      }
+      // A layer without a data tag has no tiles in any encoding.
+      Event::Empty(tag) if is_layer(&tag, layer)? => return Err(not_csv()),
+      Event::Eof => return Err(TiledError::MissingLayer(layer.to_string())),
+      _ => {}
+    }
+  };
+
+  let csv = loop {
+    match reader.read_event()? {
+      Event::Start(tag) if tag.name().as_ref() == b"data" => {
+        if attribute(&tag, "encoding")?.as_deref() != Some("csv") {
+          return Err(not_csv());
+        }
+        break reader.read_text(tag.name())?;
+      }
+      Event::Empty(tag) if tag.name().as_ref() == b"data" => {
+        if attribute(&tag, "encoding")?.as_deref() != Some("csv") {
+          return Err(not_csv());
+        }
+        break "".into();
+      }
+      Event::End(tag) if tag.name().as_ref() == b"layer" => return Err(not_csv()),
+      Event::Eof => return Err(not_csv()),
+      _ => {}
+    }
+  };
+
+  let gids = parse_gids(&csv)?;
+  Ok(match width {
+    Some(width) if width > 0 => gids.chunks(width).map(|row| row.to_vec()).collect(),
+    _ => vec![gids],
+  })
+}
+
+/**
+ * Reads the tile ids of a layer exported from Tiled as a `.csv` file, one row per line.
+ */
+pub fn layer_from_csv(csv: &str) -> Result<Vec<Vec<u32>>, TiledError> {
+  csv
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .map(parse_gids)
+    .collect()
+}
+
+/**
+ * Turns tile ids into tile types, so the grid can be passed to `learn_rules_from_grid`.
+ * Every id has to be in the lookup, empty cells included.
+ */
+pub fn grid_from_gids(
+  gids: &[Vec<u32>],
+  lookup: &HashMap<u32, TileType>,
+) -> Result<Vec<Vec<TileType>>, TiledError> {
+  gids
+    .iter()
+    .enumerate()
+    .map(|(y, row)| {
+      row
+        .iter()
+        .enumerate()
+        .map(|(x, gid)| {
+          let gid = gid & !FLIP_FLAGS;
+          lookup
+            .get(&gid)
+            .cloned()
+            .ok_or(TiledError::UnknownGid { gid, x, y })
+        })
+        .collect()
+    })
+    .collect()
+}
+
+/**
+ * Turns tile ids into cells for `TileMap::load_partial`.
+ * Ids missing from the lookup, like empty cells, are left out so they get generated.
+ */
+pub fn cells_from_gids(
+  gids: &[Vec<u32>],
+  lookup: &HashMap<u32, TileType>,
+) -> HashMap<Position, TileType> {
+  let mut cells = HashMap::new();
+
+  for (y, row) in gids.iter().enumerate() {
+    for (x, gid) in row.iter().enumerate() {
+      if let Some(tile_type) = lookup.get(&(gid & !FLIP_FLAGS)) {
+        let position = Position {
+          x: x as i32,
+          y: y as i32,
+          z: 0,
+        };
+        cells.insert(position, tile_type.clone());
+      }
+    }
+  }
+
+  cells
+}
+
+fn parse_gids(csv: &str) -> Result<Vec<u32>, TiledError> {
+  csv
+    .split(',')
+    .map(|value| value.trim())
+    .filter(|value| !value.is_empty())
+    .map(|value| {
+      value
+        .parse()
+        .map_err(|_| TiledError::InvalidGid(value.to_string()))
+    })
+    .collect()
+}
+
+fn is_layer(tag: &BytesStart, layer: &str) -> Result<bool, TiledError> {
+  Ok(tag.name().as_ref() == b"layer" && attribute(tag, "name")?.as_deref() == Some(layer))
+}
+
+/**
+ * The value of an attribute of a tag with its entities replaced, `None` when the tag doesn't have it.
+ */
+fn attribute(tag: &BytesStart, name: &str) -> Result<Option<String>, TiledError> {
+  let attribute = match tag
+    .try_get_attribute(name)
+    .map_err(quick_xml::Error::from)?
+  {
+    Some(attribute) => attribute,
+    None => return Ok(None),
+  };
+
+  Ok(Some(attribute.unescape_value()?.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn example_map_has_a_ground_layer() {
+    let gids = layer_from_tmx(include_str!("../../assets/maps/island.tmx"), "ground").unwrap();
+    assert_eq!(gids.len(), 10);
+    assert!(gids.iter().all(|row| row.len() == 20));
+  }
+
+  #[test]
+  fn layer_is_found_however_its_tag_is_written() {
+    let tmx = r#"<map>
+      <layer id='1' name='trees' width='2' height='1'><data encoding='csv'>4,4</data></layer>
+      <layer id='2'
+        name='gro&amp;und' width='2'
+        height='2'>
+        <properties><property name="name" value="ground"/></properties>
+        <data encoding="csv">
+1,2,
+3,0
+</data>
+      </layer>
+    </map>"#;
+
+    assert_eq!(
+      layer_from_tmx(tmx, "gro&und").unwrap(),
+      vec![vec![1, 2], vec![3, 0]]
+    );
+    assert_eq!(
+      layer_from_tmx(tmx, "ground"),
+      Err(TiledError::MissingLayer("ground".to_string()))
+    );
+  }
+
+  #[test]
+  fn layers_in_other_encodings_are_rejected() {
+    let tmx = r#"<map><layer name="ground" width="1"><data encoding="base64">AQAAAA==</data></layer></map>"#;
+    assert_eq!(
+      layer_from_tmx(tmx, "ground"),
+      Err(TiledError::NotCsv("ground".to_string()))
+    );
+  }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+  tile_map::Direction,
+  tile_set::TileSet,
+  tile_type::{AdjacencyRule, TileRules, TileType, DEFAULT_TILESET_COLUMNS, DEFAULT_TILE_SIZE},
+};
+
+/**
+ * The colour of each edge of a Wang tile.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WangEdges {
+  pub north: String,
+  pub east: String,
+  pub south: String,
+  pub west: String,
+}
+
+impl WangEdges {
+  pub fn get(&self, direction: &Direction) -> Option<&String> {
+    match direction {
+      Direction::North => Some(&self.north),
+      Direction::East => Some(&self.east),
+      Direction::South => Some(&self.south),
+      Direction::West => Some(&self.west),
+      _ => None,
+    }
+  }
+}
+
+/**
+ * Derives adjacency from edge colours: a tile can be next to another when the edges they touch with match.
+ * Diagonals allow anything, types without edges get no neighbours.
+ */
+pub fn adjacency_from_edges(
+  edges: &HashMap<TileType, WangEdges>,
+) -> HashMap<TileType, AdjacencyRule> {
+  TileType::all_types()
+    .into_iter()
+    .map(|tile_type| {
+      let matching = |direction: Direction| -> TileSet {
+        let edge = match edges.get(&tile_type).and_then(|tile| tile.get(&direction)) {
+          Some(edge) => edge,
+          None => return TileSet::empty(),
+        };
+
+        edges
+          .iter()
+          .filter(|(_, other)| other.get(&direction.opposite()) == Some(edge))
+          .map(|(other_type, _)| other_type.clone())
+          .collect()
+      };
+
+      let rule = AdjacencyRule::new(
+        matching(Direction::North),
+        matching(Direction::East),
+        matching(Direction::South),
+        matching(Direction::West),
+      );
+      (tile_type, rule)
+    })
+    .collect()
+}
+
+/**
+ * Rules with the adjacency derived from `edges`, and no cleanup or tags.
+ */
+pub fn rules_from_edges(
+  edges: &HashMap<TileType, WangEdges>,
+  weights: HashMap<TileType, f32>,
+) -> TileRules {
+  TileRules {
+    adjacency: adjacency_from_edges(edges),
+    weights,
+    cleanup: Vec::new(),
+    tags: HashMap::new(),
+    tag_adjacency: Vec::new(),
+    merged: HashMap::new(),
+    texture_rules: Vec::new(),
+    forbidden_corners: Vec::new(),
+    directional_weights: HashMap::new(),
+    metadata: HashMap::new(),
+    tile_width: DEFAULT_TILE_SIZE,
+    tile_height: DEFAULT_TILE_SIZE,
+    tileset_columns: DEFAULT_TILESET_COLUMNS,
+  }
+}